@@ -1,3 +1,7 @@
+#[cfg(not(feature = "fluidsynth"))]
+fn main() {}
+
+#[cfg(feature = "fluidsynth")]
 fn main() {
     if cfg!(target_os = "windows") {
         return;
@@ -1,110 +1,578 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    ffi::{c_int, c_void, CString},
+    path::Path,
+    ptr::null_mut,
+    slice,
+    sync::Mutex,
+};
+
 use crate::bindings::{
-    _fluid_audio_driver_t, _fluid_hashtable_t, _fluid_synth_t, delete_fluid_audio_driver,
-    delete_fluid_settings, delete_fluid_synth, fluid_settings_setint, fluid_settings_setstr,
-    fluid_synth_activate_tuning, fluid_synth_all_notes_off, fluid_synth_cc, fluid_synth_noteoff,
-    fluid_synth_noteon, fluid_synth_program_change, fluid_synth_sfload, fluid_synth_tune_notes,
-    new_fluid_audio_driver, new_fluid_settings, new_fluid_synth, FLUID_OK,
+    _fluid_audio_driver_t, _fluid_file_renderer_t, _fluid_hashtable_t, _fluid_player_t,
+    _fluid_synth_t, delete_fluid_audio_driver, delete_fluid_file_renderer, delete_fluid_player,
+    delete_fluid_settings, delete_fluid_synth, fluid_file_renderer_process_block,
+    fluid_midi_event_get_channel, fluid_midi_event_set_channel, fluid_midi_event_t,
+    fluid_player_add, fluid_player_get_status, fluid_player_play, fluid_player_seek,
+    fluid_player_set_playback_callback, fluid_player_status_FLUID_PLAYER_DONE,
+    fluid_player_status_FLUID_PLAYER_PLAYING, fluid_player_stop, fluid_settings_setint,
+    fluid_settings_setnum, fluid_settings_setstr, fluid_synth_activate_tuning,
+    fluid_synth_all_notes_off, fluid_synth_bank_select, fluid_synth_cc,
+    fluid_synth_handle_midi_event, fluid_synth_noteoff, fluid_synth_noteon, fluid_synth_process,
+    fluid_synth_program_change, fluid_synth_program_select, fluid_synth_set_bank_offset,
+    fluid_synth_set_channel_type, fluid_synth_set_interp_method, fluid_synth_sfload,
+    fluid_synth_tune_notes, new_fluid_audio_driver2, new_fluid_file_renderer, new_fluid_player,
+    new_fluid_settings, new_fluid_synth, CHANNEL_TYPE_DRUM, FLUID_OK,
 };
+use crate::recording::{Buffers, Recording};
+use crate::settings::{AudioSettings, SoundfontSettings, TemperamentSettings};
+use crate::synth_backend::{SynthBackend, SynthStatus, SYNTH_CHANNELS};
+use crate::synthctrler::Transport;
+use crate::temperament::Temperament;
+
+/// 伴奏はキーボードの使う 0..16 と重ならないよう、16 チャンネルずらして鳴らす
+const BACKING_TRACK_CHANNEL_OFFSET: i32 = SYNTH_CHANNELS as i32 / 2;
+const PERCUSSION_CHANNEL: i32 = 9;
+
+unsafe extern "C" fn play_backing_track(
+    data: *mut c_void,
+    event: *mut fluid_midi_event_t,
+) -> c_int {
+    let chan = fluid_midi_event_get_channel(event);
+    fluid_midi_event_set_channel(event, chan + BACKING_TRACK_CHANNEL_OFFSET);
+    fluid_synth_handle_midi_event(data, event)
+}
+
+/// FluidSynth の関数の戻り値を確かめ、失敗していれば知らせる
+fn check(name: &str, result: c_int) -> bool {
+    let ok = result as u32 == FLUID_OK;
+    if !ok {
+        eprintln!("{} failed", name);
+    }
+    ok
+}
+
+/// オーディオドライバーのコールバックに渡す状態
+struct Capture {
+    synth: *mut _fluid_synth_t,
+    /// 録音中なら出力した音声の送り先
+    buffers: Mutex<Option<Buffers>>,
+}
+
+/// 合成した音声をオーディオデバイスに出しつつ、録音中なら複製して送る
+unsafe extern "C" fn process(
+    data: *mut c_void,
+    len: c_int,
+    nfx: c_int,
+    fx: *mut *mut f32,
+    nout: c_int,
+    out: *mut *mut f32,
+) -> c_int {
+    let capture = &*(data as *const Capture);
+    let len = len as usize;
+    let buffers = |ptr: *mut *mut f32, count: c_int| {
+        if ptr.is_null() || count <= 0 {
+            return &[][..];
+        }
+        slice::from_raw_parts(ptr, count as usize)
+    };
+    let (fx_buffers, buffers) = (buffers(fx, nfx), buffers(out, nout));
+    for &buffer in buffers.iter().chain(fx_buffers) {
+        slice::from_raw_parts_mut(buffer, len).fill(0.0);
+    }
+    let result = fluid_synth_process(capture.synth, len as c_int, nfx, fx, nout, out);
+    if let (Ok(recording), [left, right, ..]) = (capture.buffers.try_lock(), buffers) {
+        if let Some(recording) = recording.as_ref() {
+            recording.send(
+                slice::from_raw_parts(*left, len),
+                slice::from_raw_parts(*right, len),
+            );
+        }
+    }
+    result
+}
 
 pub struct FluidSynth {
     settings: *mut _fluid_hashtable_t,
     synth: *mut _fluid_synth_t,
+    /// オフラインで書き出すときは null
     driver: *mut _fluid_audio_driver_t,
+    /// オーディオデバイスに出力するときは null
+    renderer: *mut _fluid_file_renderer_t,
+    /// renderer で書き出し済みのフレーム数
+    rendered_frames: Cell<u64>,
+    sample_rate: f64,
+    period_size: i32,
+    capture: Box<Capture>,
+    recording: RefCell<Option<Recording>>,
+    recording_dir: String,
+    /// 伴奏を初めて再生するまでは null
+    player: Cell<*mut _fluid_player_t>,
+    backing_track: String,
+    /// 設定の soundfonts の順に (読み込んだ ID, バンクオフセット)。読み込みに失敗したものは None
+    sfonts: Vec<Option<(i32, i32)>>,
+    /// チャンネルごとに選択中の (soundfont の番号, バンク)
+    banks: RefCell<HashMap<u8, (Option<u8>, u16)>>,
+    /// 設定の temperaments の順。読み込みに失敗したものは平均律
+    temperaments: Vec<Temperament>,
+    /// チャンネルごとに選択中の (temperaments の番号, 主音)
+    channel_temperaments: RefCell<HashMap<u8, (u8, u8)>>,
+    /// チャンネルごとの (半音の 1/12 単位のずれ, セント単位の微調整)
+    channel_tunings: RefCell<HashMap<u8, (i32, i32)>>,
+    a4_hz: Cell<u16>,
 }
 
 impl FluidSynth {
-    pub fn new() -> Self {
+    pub fn new(
+        audio: &AudioSettings,
+        recording_dir: &str,
+        soundfonts: &[SoundfontSettings],
+        temperaments: &[TemperamentSettings],
+        backing_track: &str,
+    ) -> Self {
+        Self::create(
+            audio,
+            recording_dir,
+            soundfonts,
+            temperaments,
+            backing_track,
+            None,
+        )
+    }
+
+    /// オーディオデバイスを使わず、render_until で WAV ファイルに書き出す
+    pub fn offline(
+        audio: &AudioSettings,
+        recording_dir: &str,
+        soundfonts: &[SoundfontSettings],
+        temperaments: &[TemperamentSettings],
+        backing_track: &str,
+        path: &str,
+    ) -> Self {
+        Self::create(
+            audio,
+            recording_dir,
+            soundfonts,
+            temperaments,
+            backing_track,
+            Some(path),
+        )
+    }
+
+    fn create(
+        audio: &AudioSettings,
+        recording_dir: &str,
+        soundfonts: &[SoundfontSettings],
+        temperaments: &[TemperamentSettings],
+        backing_track: &str,
+        file: Option<&str>,
+    ) -> Self {
         unsafe {
             let settings = new_fluid_settings();
 
-            fluid_settings_setstr(
-                settings,
-                "audio.driver\0".as_ptr() as *const _,
-                "alsa\0".as_ptr() as *const _,
-            );
-            fluid_settings_setstr(
-                settings,
-                "audio.sdl2.device\0".as_ptr() as *const _,
-                "default\0".as_ptr() as *const _,
+            let name = |name: &str| CString::new(name).unwrap();
+            let report = |name: &CString, result: i32| {
+                if result as u32 != FLUID_OK {
+                    eprintln!("failed to set {}", name.to_string_lossy());
+                }
+            };
+            let key = name("audio.driver");
+            let driver = name(audio.driver());
+            report(
+                &key,
+                fluid_settings_setstr(settings, key.as_ptr(), driver.as_ptr()),
             );
-            fluid_settings_setint(settings, "audio.periods\0".as_ptr() as *const _, 4);
-            fluid_settings_setint(settings, "audio.period-size\0".as_ptr() as *const _, 444);
+            if let Some(device) = audio.device() {
+                let key = name(&format!("audio.{}.device", audio.driver()));
+                let device = name(device);
+                report(
+                    &key,
+                    fluid_settings_setstr(settings, key.as_ptr(), device.as_ptr()),
+                );
+            }
+            for (key, value) in [
+                ("audio.periods", audio.periods()),
+                ("audio.period-size", audio.period_size()),
+                ("synth.polyphony", audio.polyphony()),
+                ("synth.midi-channels", SYNTH_CHANNELS as i32),
+            ] {
+                let key = name(key);
+                report(&key, fluid_settings_setint(settings, key.as_ptr(), value));
+            }
+            for (key, value) in [
+                ("synth.sample-rate", audio.sample_rate()),
+                ("synth.gain", audio.gain()),
+            ] {
+                let key = name(key);
+                report(&key, fluid_settings_setnum(settings, key.as_ptr(), value));
+            }
+            if let Some(file) = file {
+                for (key, value) in [
+                    ("audio.file.name", file),
+                    ("audio.file.type", "wav"),
+                    ("player.timing-source", "sample"),
+                ] {
+                    let key = name(key);
+                    let value = name(value);
+                    report(
+                        &key,
+                        fluid_settings_setstr(settings, key.as_ptr(), value.as_ptr()),
+                    );
+                }
+                let key = name("synth.lock-memory");
+                report(&key, fluid_settings_setint(settings, key.as_ptr(), 0));
+            }
 
             let synth = new_fluid_synth(settings);
-            let driver = new_fluid_audio_driver(settings, synth);
-            fluid_synth_sfload(
+            check(
+                "fluid_synth_set_interp_method",
+                fluid_synth_set_interp_method(synth, -1, audio.interpolation()),
+            );
+            check(
+                "fluid_synth_set_channel_type",
+                fluid_synth_set_channel_type(
+                    synth,
+                    PERCUSSION_CHANNEL + BACKING_TRACK_CHANNEL_OFFSET,
+                    CHANNEL_TYPE_DRUM as i32,
+                ),
+            );
+            let capture = Box::new(Capture {
                 synth,
-                "/usr/share/sounds/sf2/FluidR3_GM.sf2\0".as_ptr() as *const _,
-                1,
+                buffers: Mutex::new(None),
+            });
+            let (driver, renderer) = match file {
+                None => {
+                    let driver = new_fluid_audio_driver2(
+                        settings,
+                        Some(process),
+                        &*capture as *const Capture as *mut c_void,
+                    );
+                    if driver.is_null() {
+                        eprintln!("failed to start audio driver: {}", audio.driver());
+                    }
+                    (driver, null_mut())
+                }
+                Some(file) => {
+                    let renderer = new_fluid_file_renderer(synth);
+                    if renderer.is_null() {
+                        eprintln!("failed to open {}", file);
+                    }
+                    (null_mut(), renderer)
+                }
+            };
+            let sfonts: Vec<_> = soundfonts
+                .iter()
+                .map(|soundfont| {
+                    let path = CString::new(soundfont.path().as_str()).ok()?;
+                    let id = fluid_synth_sfload(synth, path.as_ptr(), 1);
+                    if id < 0 {
+                        eprintln!("failed to load soundfont: {}", soundfont.path());
+                        return None;
+                    }
+                    check(
+                        "fluid_synth_set_bank_offset",
+                        fluid_synth_set_bank_offset(synth, id, soundfont.bank_offset()),
+                    );
+                    Some((id, soundfont.bank_offset()))
+                })
+                .collect();
+            if sfonts.iter().all(|x| x.is_none()) {
+                eprintln!("no soundfont loaded");
+            }
+            // 打楽器の音色はチャンネルの種類を変えたあとのプログラムチェンジで選ばれる
+            check(
+                "fluid_synth_program_change",
+                fluid_synth_program_change(
+                    synth,
+                    PERCUSSION_CHANNEL + BACKING_TRACK_CHANNEL_OFFSET,
+                    0,
+                ),
             );
             Self {
                 settings,
                 synth,
                 driver,
+                renderer,
+                rendered_frames: Cell::new(0),
+                sample_rate: audio.sample_rate(),
+                period_size: audio.period_size(),
+                capture,
+                recording: RefCell::new(None),
+                recording_dir: recording_dir.to_owned(),
+                player: Cell::new(null_mut()),
+                backing_track: backing_track.to_owned(),
+                sfonts,
+                banks: Default::default(),
+                temperaments: temperaments.iter().map(Temperament::load).collect(),
+                channel_temperaments: Default::default(),
+                channel_tunings: Default::default(),
+                a4_hz: Cell::new(440),
+            }
+        }
+    }
+
+    /// 書き出しを開始から ms ミリ秒の位置まで進める
+    pub fn render_until(&self, ms: u64) -> bool {
+        if self.renderer.is_null() {
+            return false;
+        }
+        let frames = (ms as f64 * self.sample_rate / 1000.0) as u64;
+        while self.rendered_frames.get() < frames {
+            if !check("fluid_file_renderer_process_block", unsafe {
+                fluid_file_renderer_process_block(self.renderer)
+            }) {
+                return false;
             }
+            self.rendered_frames
+                .set(self.rendered_frames.get() + self.period_size as u64);
         }
+        true
     }
 
-    pub fn noteon(&self, chan: u8, key: u8, vel: u8) -> bool {
+    /// チューニングのバンク 0 にチャンネル番号と同じプログラムを作り、そのチャンネルでだけ使う
+    fn retune(&self, chan: u8) -> bool {
+        let (temperament, root) = self
+            .channel_temperaments
+            .borrow()
+            .get(&chan)
+            .copied()
+            .unwrap_or_default();
+        let (tuning, fine_tune) = self
+            .channel_tunings
+            .borrow()
+            .get(&chan)
+            .copied()
+            .unwrap_or_default();
+        let Some(temperament) = self.temperaments.get(temperament as usize) else {
+            eprintln!("temperament {} not found", temperament);
+            return false;
+        };
+        let keys: Vec<i32> = (0..=127).collect();
+        let offset = tuning as f64 * 100.0 / 12.0 + fine_tune as f64;
+        let pitch: Vec<f64> = temperament
+            .pitches(root, self.a4_hz.get())
+            .iter()
+            .map(|&cents| cents + offset)
+            .collect();
+        let prog = chan as i32;
+        unsafe {
+            check(
+                "fluid_synth_tune_notes",
+                fluid_synth_tune_notes(self.synth, 0, prog, 128, keys.as_ptr(), pitch.as_ptr(), 1),
+            ) && check(
+                "fluid_synth_activate_tuning",
+                fluid_synth_activate_tuning(self.synth, chan as i32, 0, prog, 1),
+            )
+        }
+    }
+}
+
+impl SynthBackend for FluidSynth {
+    fn noteon(&self, chan: u8, key: u8, vel: u8) -> bool {
         debug_assert!((1..=127).contains(&vel));
-        (unsafe { fluid_synth_noteon(self.synth, chan as i32, key as i32, vel as i32) }) as u32
-            == FLUID_OK
+        check("fluid_synth_noteon", unsafe {
+            fluid_synth_noteon(self.synth, chan as i32, key as i32, vel as i32)
+        })
     }
 
-    pub fn noteoff(&self, chan: u8, key: u8) -> bool {
-        (unsafe { fluid_synth_noteoff(self.synth, chan as i32, key as i32) }) as u32 == FLUID_OK
+    /// 鳴っていない音 (ホールドで止めた音や鳴り終わった打楽器など) を離すと
+    /// FLUID_FAILED が返るが、止める音が無いだけなので失敗とはしない。
+    /// 引数の誤りは FluidSynth を呼ぶ前に確かめて知らせる
+    fn noteoff(&self, chan: u8, key: u8) -> bool {
+        if chan >= SYNTH_CHANNELS || key > 127 {
+            eprintln!(
+                "fluid_synth_noteoff: invalid channel {} or key {}",
+                chan, key
+            );
+            return false;
+        }
+        unsafe { fluid_synth_noteoff(self.synth, chan as i32, key as i32) };
+        true
     }
 
-    pub fn all_notes_off(&self, chan: u8) -> bool {
-        (unsafe { fluid_synth_all_notes_off(self.synth, chan as i32) }) as u32 == FLUID_OK
+    fn all_notes_off(&self, chan: u8) -> bool {
+        check("fluid_synth_all_notes_off", unsafe {
+            fluid_synth_all_notes_off(self.synth, chan as i32)
+        })
     }
 
-    pub fn tuning(&self, tuning: i32) -> bool {
-        let keys: Vec<i32> = (0..=127).collect();
-        let pitch: Vec<f64> = keys
-            .iter()
-            .map(|&i| (i as f64 + tuning as f64 / 12.0) * 100.0)
-            .collect();
-        (0..16).all(|chan| {
-            (unsafe {
-                fluid_synth_tune_notes(self.synth, 0, 0, 128, keys.as_ptr(), pitch.as_ptr(), 1)
-            }) as u32
-                == FLUID_OK
-                && (unsafe { fluid_synth_activate_tuning(self.synth, chan, 0, 0, 1) }) as u32
-                    == FLUID_OK
+    fn tuning(&self, chan: u8, tuning: i32, fine_tune: i32) -> bool {
+        self.channel_tunings
+            .borrow_mut()
+            .insert(chan, (tuning, fine_tune));
+        self.retune(chan)
+    }
+
+    fn temperament(&self, chan: u8, temperament: u8, root: u8) -> bool {
+        self.channel_temperaments
+            .borrow_mut()
+            .insert(chan, (temperament, root));
+        self.retune(chan)
+    }
+
+    /// 伴奏のチャンネルも平均律のまま基準ピッチに合わせる
+    fn concert_pitch(&self, a4_hz: u16) -> bool {
+        self.a4_hz.set(a4_hz);
+        let failed = (0..SYNTH_CHANNELS)
+            .filter(|&chan| !self.retune(chan))
+            .count();
+        failed == 0
+    }
+
+    fn cc(&self, chan: u8, ctrl: u8, value: u8) -> bool {
+        check("fluid_synth_cc", unsafe {
+            fluid_synth_cc(self.synth, chan as i32, ctrl as i32, value as i32)
         })
     }
 
-    pub fn hold(&self, chan: u8, value: bool) -> bool {
-        (unsafe { fluid_synth_cc(self.synth, chan as i32, 64, if value { 127 } else { 0 }) }) as u32
-            == FLUID_OK
+    fn program_change(&self, chan: u8, program: u8) -> bool {
+        let (soundfont, bank) = self.banks.borrow().get(&chan).copied().unwrap_or_default();
+        let Some(soundfont) = soundfont else {
+            return check("fluid_synth_program_change", unsafe {
+                fluid_synth_program_change(self.synth, chan as i32, program as i32)
+            });
+        };
+        let Some(&Some((id, offset))) = self.sfonts.get(soundfont as usize) else {
+            eprintln!("soundfont {} is not loaded", soundfont);
+            return false;
+        };
+        check("fluid_synth_program_select", unsafe {
+            fluid_synth_program_select(
+                self.synth,
+                chan as i32,
+                id,
+                bank as i32 + offset,
+                program as i32,
+            )
+        })
     }
 
-    pub fn modulation(&self, chan: u8, value: bool) -> bool {
-        (unsafe { fluid_synth_cc(self.synth, chan as i32, 1, if value { 127 } else { 0 }) }) as u32
-            == FLUID_OK
+    fn recording(&self, on: bool) -> bool {
+        let mut recording = self.recording.borrow_mut();
+        if !on {
+            *self.capture.buffers.lock().unwrap() = None;
+            if let Some(recording) = recording.take() {
+                recording.stop();
+            }
+            return true;
+        }
+        if recording.is_some() {
+            return true;
+        }
+        if self.driver.is_null() {
+            return false;
+        }
+        match Recording::start(
+            &self.recording_dir,
+            self.sample_rate as u32,
+            self.period_size as usize,
+        ) {
+            Ok((new_recording, buffers)) => {
+                *self.capture.buffers.lock().unwrap() = Some(buffers);
+                *recording = Some(new_recording);
+                true
+            }
+            Err(err) => {
+                eprintln!("{}", err);
+                false
+            }
+        }
     }
 
-    pub fn reverb(&self, chan: u8, value: bool) -> bool {
-        (unsafe { fluid_synth_cc(self.synth, chan as i32, 91, if value { 127 } else { 0 }) }) as u32
-            == FLUID_OK
+    fn backing_track(&self, transport: Transport) -> bool {
+        let mut player = self.player.get();
+        if player.is_null() {
+            if transport != Transport::Start {
+                return true;
+            }
+            let Ok(path) = CString::new(self.backing_track.as_str()) else {
+                return false;
+            };
+            if !Path::new(&self.backing_track).is_file() {
+                eprintln!("backing track not found: {}", self.backing_track);
+                return false;
+            }
+            player = unsafe { new_fluid_player(self.synth) };
+            if player.is_null() {
+                return false;
+            }
+            let ok = unsafe {
+                check(
+                    "fluid_player_set_playback_callback",
+                    fluid_player_set_playback_callback(
+                        player,
+                        Some(play_backing_track),
+                        self.synth as *mut c_void,
+                    ),
+                ) && check("fluid_player_add", fluid_player_add(player, path.as_ptr()))
+            };
+            if !ok {
+                unsafe { delete_fluid_player(player) };
+                return false;
+            }
+            self.player.set(player);
+        }
+        unsafe {
+            match transport {
+                Transport::Start => {
+                    if fluid_player_get_status(player) as u32
+                        == fluid_player_status_FLUID_PLAYER_DONE
+                        && !check("fluid_player_seek", fluid_player_seek(player, 0))
+                    {
+                        return false;
+                    }
+                    check("fluid_player_play", fluid_player_play(player))
+                }
+                Transport::Stop => {
+                    let result = check("fluid_player_stop", fluid_player_stop(player));
+                    let failed = (BACKING_TRACK_CHANNEL_OFFSET..SYNTH_CHANNELS as i32)
+                        .filter(|&chan| {
+                            !check(
+                                "fluid_synth_all_notes_off",
+                                fluid_synth_all_notes_off(self.synth, chan),
+                            )
+                        })
+                        .count();
+                    result && failed == 0
+                }
+                Transport::Rewind => check("fluid_player_seek", fluid_player_seek(player, 0)),
+            }
+        }
     }
 
-    pub fn chorus(&self, chan: u8, value: bool) -> bool {
-        (unsafe { fluid_synth_cc(self.synth, chan as i32, 93, if value { 127 } else { 0 }) }) as u32
-            == FLUID_OK
+    fn bank_select(&self, chan: u8, soundfont: Option<u8>, bank: u16) -> bool {
+        self.banks.borrow_mut().insert(chan, (soundfont, bank));
+        check("fluid_synth_bank_select", unsafe {
+            fluid_synth_bank_select(self.synth, chan as i32, bank as i32)
+        })
     }
 
-    pub fn program_change(&self, chan: u8, program: u8) -> bool {
-        (unsafe { fluid_synth_program_change(self.synth, chan as i32, program as i32) }) as u32
-            == FLUID_OK
+    fn status(&self) -> SynthStatus {
+        let player = self.player.get();
+        SynthStatus {
+            recording: self.recording.borrow().is_some(),
+            backing_track: !player.is_null()
+                && (unsafe { fluid_player_get_status(player) }) as u32
+                    == fluid_player_status_FLUID_PLAYER_PLAYING,
+        }
     }
 }
 
 impl Drop for FluidSynth {
     fn drop(&mut self) {
         unsafe {
-            delete_fluid_audio_driver(self.driver);
+            let player = self.player.get();
+            if !player.is_null() {
+                fluid_player_stop(player);
+                delete_fluid_player(player);
+            }
+            if !self.renderer.is_null() {
+                delete_fluid_file_renderer(self.renderer);
+            }
+            if !self.driver.is_null() {
+                delete_fluid_audio_driver(self.driver);
+            }
+            self.recording(false);
             delete_fluid_synth(self.synth);
             delete_fluid_settings(self.settings);
         }
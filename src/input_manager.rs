@@ -1,70 +1,164 @@
 use std::{
     collections::HashMap,
-    sync::{mpsc, Arc, RwLock},
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex, RwLock,
+    },
     thread::{sleep, spawn},
     time::Duration,
 };
 
 use evdev::{Device, InputEventKind};
+use inotify::{Inotify, WatchMask};
 
-use crate::kmctrler::{Event, Input};
+#[cfg(feature = "alsa")]
+use crate::midi_input::start_midi_inputs;
+use crate::{
+    kmctrler::Event,
+    settings::{DeviceProfile, DeviceRegistry},
+};
+
+fn find_profile<'a>(profiles: &'a [DeviceProfile], dev: &Device) -> Option<&'a DeviceProfile> {
+    profiles.iter().find(|profile| {
+        profile
+            .name()
+            .as_ref()
+            .is_none_or(|name| dev.name() == Some(name))
+            && profile
+                .vendor()
+                .is_none_or(|vendor| dev.input_id().vendor() == vendor)
+            && profile
+                .product()
+                .is_none_or(|product| dev.input_id().product() == product)
+            && profile.key_count().is_none_or(|key_count| {
+                dev.supported_keys().map_or(0, |keys| keys.iter().count()) == key_count
+            })
+    })
+}
+
+/// シリアル番号があればそれを、無ければ物理パスをデバイスの識別子とする
+fn device_id(dev: &Device) -> Option<&str> {
+    dev.unique_name()
+        .filter(|x| !x.is_empty())
+        .or_else(|| dev.physical_path())
+}
+
+const POLLING_INTERVAL: Duration = Duration::from_secs(3);
+/// inotify が使える場合でも取りこぼしに備えて定期的に列挙し直す
+const FALLBACK_POLLING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// /dev/input に evdev のノードが追加されたら通知する
+fn watch_input_nodes() -> Option<mpsc::Receiver<()>> {
+    let mut inotify = match Inotify::init() {
+        Ok(inotify) => inotify,
+        Err(err) => {
+            eprintln!("{}", err);
+            return None;
+        }
+    };
+    if let Err(err) = inotify
+        .watches()
+        .add("/dev/input", WatchMask::CREATE | WatchMask::ATTRIB)
+    {
+        eprintln!("{}", err);
+        return None;
+    }
+    let (tx, rx) = mpsc::channel();
+    spawn(move || {
+        let mut buffer = [0; 1024];
+        loop {
+            let Ok(events) = inotify.read_events_blocking(&mut buffer) else {
+                return;
+            };
+            let mut events = events.filter(|ev| {
+                ev.name
+                    .is_some_and(|name| name.to_string_lossy().starts_with("event"))
+            });
+            if events.next().is_some() && tx.send(()).is_err() {
+                return;
+            }
+        }
+    });
+    Some(rx)
+}
 
-fn is_km_ctrler(dev: &Device) -> bool {
-    dev.name() == Some("KONAMI USB Multipurpose Controller")
-        && dev.supported_keys().unwrap().iter().count() == 34
+/// デバイスノードの追加を待つ。inotify が使えなければ一定間隔のポーリングになる
+fn wait_for_devices(hotplug: &mut Option<mpsc::Receiver<()>>) {
+    let Some(rx) = hotplug else {
+        sleep(POLLING_INTERVAL);
+        return;
+    };
+    match rx.recv_timeout(FALLBACK_POLLING_INTERVAL) {
+        Ok(()) => while rx.try_recv().is_ok() {},
+        Err(RecvTimeoutError::Timeout) => {}
+        Err(RecvTimeoutError::Disconnected) => *hotplug = None,
+    }
 }
 
-pub fn start_inputs() -> mpsc::Receiver<(usize, Event)> {
+pub fn start_inputs(
+    registry: DeviceRegistry,
+    profiles: Vec<DeviceProfile>,
+) -> mpsc::Receiver<(usize, Event)> {
     let devices = Arc::new(RwLock::new(HashMap::new()));
+    let registry = Arc::new(Mutex::new(registry));
 
     let (tx, rx) = mpsc::channel();
+    #[cfg(feature = "alsa")]
+    start_midi_inputs(registry.clone(), tx.clone());
     {
         let devices = devices.clone();
+        let mut hotplug = watch_input_nodes();
         spawn(move || loop {
             evdev::enumerate()
                 .filter(|(_, dev)| {
-                    is_km_ctrler(dev)
+                    dev.physical_path().is_some()
                         && !devices
                             .read()
                             .unwrap()
                             .contains_key(dev.physical_path().unwrap())
                 })
-                .for_each(|(_, mut dev)| {
+                .filter_map(|(_, dev)| {
+                    let key_map = find_profile(&profiles, &dev)?.key_map().clone();
+                    Some((dev, key_map))
+                })
+                .for_each(|(mut dev, key_map)| {
                     let physical_path = dev.physical_path().unwrap().to_owned();
-                    println!("{:?}", physical_path);
-                    devices.write().unwrap().insert(physical_path.clone(), ());
+                    let Some(idx) = registry.lock().unwrap().slot(device_id(&dev).unwrap()) else {
+                        return;
+                    };
+                    println!("{:?} -> {}", physical_path, idx);
+                    devices.write().unwrap().insert(physical_path.clone(), idx);
                     let devices = devices.clone();
+                    let registry = registry.clone();
                     let tx = tx.clone();
                     spawn(move || loop {
                         let Ok(events) = dev.fetch_events() else {
                             devices.write().unwrap().remove(&physical_path);
+                            registry.lock().unwrap().release(idx);
+                            let _ = tx.send((idx, Event::Disconnect));
                             return;
                         };
-                        let idx = {
-                            let dev = devices.read().unwrap();
-                            let mut keys: Vec<_> = dev.keys().collect();
-                            keys.sort();
-                            keys.binary_search(&&physical_path).unwrap()
-                        };
-                        events
-                            .filter_map(|ev| {
-                                let InputEventKind::Key(key) = ev.kind() else {
-                                    return None;
-                                };
-                                Input::from_raw(key.0).map(|input| {
-                                    if ev.value() == 0 {
-                                        Event::Release(input)
-                                    } else {
-                                        Event::Press(input)
-                                    }
-                                })
+                        let events = events.filter_map(|ev| {
+                            let InputEventKind::Key(key) = ev.kind() else {
+                                return None;
+                            };
+                            key_map.input(key.0).map(|input| {
+                                if ev.value() == 0 {
+                                    Event::Release(input)
+                                } else {
+                                    Event::Press(input)
+                                }
                             })
-                            .for_each(|ev| {
-                                tx.send((idx, ev)).unwrap();
-                            });
+                        });
+                        for ev in events {
+                            // 終了処理で受け手が居なくなった
+                            if tx.send((idx, ev)).is_err() {
+                                return;
+                            }
+                        }
                     });
                 });
-            sleep(Duration::from_secs(3));
+            wait_for_devices(&mut hotplug);
         });
     }
     rx
@@ -0,0 +1,126 @@
+use std::{
+    fs::{read_to_string, File},
+    io::{self, BufWriter, Write},
+    sync::mpsc,
+    thread::{sleep, spawn},
+    time::{Duration, Instant},
+};
+
+use crate::kmctrler::{Event, Input};
+
+fn format_input(input: &Input) -> String {
+    match input {
+        Input::Key(no) => format!("key {no}"),
+        Input::WheelUp => "wheel_up".to_owned(),
+        Input::WheelDown => "wheel_down".to_owned(),
+        Input::Start => "start".to_owned(),
+        Input::Select => "select".to_owned(),
+    }
+}
+
+fn format_event(ev: &Event) -> String {
+    match ev {
+        Event::Press(input) => format!("press {}", format_input(input)),
+        Event::Release(input) => format!("release {}", format_input(input)),
+        Event::NoteOn(note, vel) => format!("note_on {note} {vel}"),
+        Event::NoteOff(note) => format!("note_off {note}"),
+        Event::Disconnect => "disconnect".to_owned(),
+    }
+}
+
+fn parse_input(words: &[&str]) -> Option<Input> {
+    match words {
+        ["key", no] => Input::key(no.parse().ok()?),
+        [name] => Input::from_name(name),
+        _ => None,
+    }
+}
+
+pub fn parse_event(words: &[&str]) -> Option<Event> {
+    match words {
+        ["press", input @ ..] => parse_input(input).map(Event::Press),
+        ["release", input @ ..] => parse_input(input).map(Event::Release),
+        ["note_on", note, vel] => Some(Event::NoteOn(note.parse().ok()?, vel.parse().ok()?)),
+        ["note_off", note] => Some(Event::NoteOff(note.parse().ok()?)),
+        ["disconnect"] => Some(Event::Disconnect),
+        _ => None,
+    }
+}
+
+/// ジャーナルの 1 行 (経過ミリ秒, キーボード番号, イベント) を読む
+///
+/// 空行と # から始まる行は読み飛ばす
+fn parse_line(line: &str) -> Option<(u64, usize, Event)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let words: Vec<_> = line.split_whitespace().collect();
+    let [timestamp, idx, ev @ ..] = words.as_slice() else {
+        return None;
+    };
+    Some((timestamp.parse().ok()?, idx.parse().ok()?, parse_event(ev)?))
+}
+
+pub fn parse(journal: &str) -> Vec<(u64, usize, Event)> {
+    journal
+        .lines()
+        .enumerate()
+        .filter_map(|(no, line)| {
+            let entry = parse_line(line);
+            let line = line.trim();
+            if entry.is_none() && !line.is_empty() && !line.starts_with('#') {
+                eprintln!("journal:{}: invalid line: {}", no + 1, line);
+            }
+            entry
+        })
+        .collect()
+}
+
+/// 入力イベントを経過時間付きでファイルに書き出しつつ、そのまま次へ流す
+pub fn record(
+    rx: mpsc::Receiver<(usize, Event)>,
+    path: &str,
+) -> io::Result<mpsc::Receiver<(usize, Event)>> {
+    let mut file = BufWriter::new(File::create(path)?);
+    let (tx, new_rx) = mpsc::channel();
+    spawn(move || {
+        let start = Instant::now();
+        for (idx, ev) in rx {
+            let timestamp = start.elapsed().as_millis();
+            if let Err(err) = writeln!(file, "{}\t{}\t{}", timestamp, idx, format_event(&ev))
+                .and_then(|_| file.flush())
+            {
+                eprintln!("{}", err);
+            }
+            if tx.send((idx, ev)).is_err() {
+                return;
+            }
+        }
+    });
+    Ok(new_rx)
+}
+
+/// 記録したジャーナルを入力イベントとして流す
+///
+/// realtime が false なら待ち時間を入れずに全て流す
+pub fn replay(path: &str, realtime: bool) -> io::Result<mpsc::Receiver<(usize, Event)>> {
+    let entries = parse(&read_to_string(path)?);
+    let (tx, rx) = mpsc::channel();
+    spawn(move || {
+        let start = Instant::now();
+        for (timestamp, idx, ev) in entries {
+            if realtime {
+                let elapsed = start.elapsed();
+                let timestamp = Duration::from_millis(timestamp);
+                if timestamp > elapsed {
+                    sleep(timestamp - elapsed);
+                }
+            }
+            if tx.send((idx, ev)).is_err() {
+                return;
+            }
+        }
+    });
+    Ok(rx)
+}
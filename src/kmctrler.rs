@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use getset::{CopyGetters, Getters};
 
 #[derive(CopyGetters, Default, Getters)]
@@ -27,6 +29,8 @@ impl State {
             Event::Release(Input::Start) => self.start = false,
             Event::Press(Input::Select) => self.select = true,
             Event::Release(Input::Select) => self.select = false,
+            Event::NoteOn(_, _) | Event::NoteOff(_) => {}
+            Event::Disconnect => *self = Self::default(),
         }
     }
 
@@ -36,6 +40,7 @@ impl State {
     }
 }
 
+#[derive(Clone, Copy)]
 pub enum Input {
     Key(u8),
     WheelUp,
@@ -45,23 +50,64 @@ pub enum Input {
 }
 
 impl Input {
-    pub fn from_raw(value: u16) -> Option<Self> {
+    /// 設定ファイル上の表記から変換する。鍵盤は番号、それ以外は名前で指定する
+    pub fn from_name(value: &str) -> Option<Self> {
         match value {
-            304..=316 => Some(Self::Key((value - 304) as u8)),
-            317 => Some(Self::Select),
-            318..=320 => Some(Self::Key((value - 318 + 13) as u8)),
-            704..=707 => Some(Self::Key((value - 704 + 15) as u8)),
-            708 => Some(Self::Start),
-            709..=713 => Some(Self::Key((value - 709 + 19) as u8)),
-            714 => Some(Self::WheelUp),
-            715 => Some(Self::WheelDown),
-            745..=750 => None,
-            _ => panic!("{value} is not a valid input"),
+            "wheel_up" => Some(Self::WheelUp),
+            "wheel_down" => Some(Self::WheelDown),
+            "start" => Some(Self::Start),
+            "select" => Some(Self::Select),
+            _ => None,
         }
     }
+
+    pub fn key(no: i64) -> Option<Self> {
+        (0..24).contains(&no).then_some(Self::Key(no as u8))
+    }
+}
+
+/// evdev のキーコードから入力への対応表
+///
+/// 値が None のコードは無視する
+#[derive(Clone)]
+pub struct KeyMap(HashMap<u16, Option<Input>>);
+
+impl KeyMap {
+    pub fn new(map: HashMap<u16, Option<Input>>) -> Self {
+        Self(map)
+    }
+
+    /// KONAMI USB Multipurpose Controller (キーボードマニア) の配列
+    pub fn konami() -> Self {
+        Self(
+            (304..=316)
+                .map(|code| (code, Some(Input::Key((code - 304) as u8))))
+                .chain([(317, Some(Input::Select))])
+                .chain((318..=320).map(|code| (code, Some(Input::Key((code - 318 + 13) as u8)))))
+                .chain((704..=707).map(|code| (code, Some(Input::Key((code - 704 + 15) as u8)))))
+                .chain([(708, Some(Input::Start))])
+                .chain((709..=713).map(|code| (code, Some(Input::Key((code - 709 + 19) as u8)))))
+                .chain([(714, Some(Input::WheelUp)), (715, Some(Input::WheelDown))])
+                .chain((745..=750).map(|code| (code, None)))
+                .collect(),
+        )
+    }
+
+    pub fn input(&self, code: u16) -> Option<Input> {
+        let Some(input) = self.0.get(&code) else {
+            eprintln!("{code} is not a valid input");
+            return None;
+        };
+        *input
+    }
 }
 
 pub enum Event {
     Press(Input),
     Release(Input),
+    /// MIDI キーボードのノートオン (ノート番号, ベロシティ)
+    NoteOn(u8, u8),
+    /// MIDI キーボードのノートオフ (ノート番号)
+    NoteOff(u8),
+    Disconnect,
 }
@@ -1,67 +1,232 @@
+#[cfg(feature = "fluidsynth")]
 mod bindings;
+#[cfg(feature = "fluidsynth")]
 mod fluid_synth;
 mod input_manager;
+mod journal;
 mod kmctrler;
+#[cfg(feature = "alsa")]
+mod midi_input;
+mod midi_output;
+#[cfg(feature = "fluidsynth")]
+mod recording;
+mod render;
+mod scenario;
 mod settings;
+mod shutdown;
+mod smf;
+mod synth_backend;
 mod synthctrler;
+mod temperament;
 
+use std::{env, fs::read_to_string, process, sync::mpsc, time::Duration};
+
+#[cfg(feature = "fluidsynth")]
 use fluid_synth::FluidSynth;
 use input_manager::start_inputs;
-use settings::SynthesizerSettings;
-use synthctrler::{v3::SynthCtrler, Event};
-
-fn process_event(synth: &FluidSynth, ev: Event) {
-    match ev {
-        Event::Noteon(chan, key, vel) => synth.noteon(chan, key, vel),
-        Event::Noteoff(chan, key) => synth.noteoff(chan, key),
-        Event::AllNotesOff(chan) => synth.all_notes_off(chan),
-        Event::ProgramChange(chan, program) => synth.program_change(chan, program),
-        Event::Tuning(tuning) => synth.tuning(tuning),
-        Event::HoldOn(chan) => synth.hold(chan, true),
-        Event::HoldOff(chan) => synth.hold(chan, false),
-        Event::ModulationOn(chan) => synth.modulation(chan, true),
-        Event::ModulationOff(chan) => synth.modulation(chan, false),
-        Event::ReverbOn(chan) => synth.reverb(chan, true),
-        Event::ReverbOff(chan) => synth.reverb(chan, false),
-        Event::ChorusOn(chan) => synth.chorus(chan, true),
-        Event::ChorusOff(chan) => synth.chorus(chan, false),
-    };
-}
+use midi_output::Router;
+use settings::{DeviceProfile, DeviceRegistry, SynthesizerSettings};
+use smf::SmfRecorder;
+use synth_backend::{Recorder, SynthBackend, SYNTH_CHANNELS};
+use synthctrler::{channel_setup, Event, Output, SynthCtrler, Transport};
+use temperament::Temperament;
 
 fn init(settings: &mut SynthesizerSettings) -> Vec<Event> {
-    settings
+    let mut events: Vec<_> = settings
         .keyboards()
         .iter()
         .enumerate()
         .flat_map(|(chan, keyboard)| {
             let chan = chan as u8;
-            [
-                Event::ProgramChange(chan, keyboard.program_no()),
-                if keyboard.reverb() {
-                    Event::ReverbOn(chan)
-                } else {
-                    Event::ReverbOff(chan)
-                },
-                if keyboard.chorus() {
-                    Event::ChorusOn(chan)
-                } else {
-                    Event::ChorusOff(chan)
-                },
-            ]
+            // 鍵盤を分けていれば左側のチャンネルも
+            let split = settings
+                .split_channel(chan)
+                .map(|split| channel_setup(split, keyboard.split_program_no(), keyboard));
+            [channel_setup(chan, keyboard.program_no(), keyboard)]
+                .into_iter()
+                .chain(split)
+                .flatten()
         })
-        .collect()
+        .collect();
+    events.insert(0, Event::ConcertPitch(settings.a4_hz()));
+    events
 }
 
-fn main() {
-    let rx = start_inputs();
-
-    let mut settings = SynthesizerSettings::load();
+/// smf を渡すと、演奏したイベントを SMF にも書き出す
+fn run(
+    synth: &impl SynthBackend,
+    mut settings: SynthesizerSettings,
+    rx: mpsc::Receiver<(usize, kmctrler::Event)>,
+    mut smf: Option<SmfRecorder>,
+) {
     let events = init(&mut settings);
-    let synth = FluidSynth::new();
     let mut synth_ctrler = SynthCtrler::new(settings, rx);
-    events.into_iter().for_each(|ev| process_event(&synth, ev));
-    loop {
-        let ev = synth_ctrler.recv().unwrap();
-        process_event(&synth, ev);
+    let process = |ev: Event| {
+        let ok = synth.process(ev);
+        if !ok {
+            eprintln!("failed to process {:?}", ev);
+        }
+        ok
+    };
+    events.into_iter().for_each(|ev| {
+        smf.iter_mut().for_each(|smf| smf.push(ev));
+        process(ev);
+    });
+    while let Ok(Output { event, cue }) = synth_ctrler.recv(synth) {
+        // 合図の音は演奏として残さない
+        if !cue {
+            smf.iter_mut().for_each(|smf| smf.push(event));
+        }
+        synth_ctrler.processed(event, process(event));
+    }
+    process(Event::BackingTrack(Transport::Stop));
+    (0..SYNTH_CHANNELS).for_each(|chan| {
+        process(Event::AllNotesOff(chan));
+    });
+    synth_ctrler.settings().flush_save();
+    if let Some(smf) = smf {
+        smf.finish();
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let arg = |name: &str| {
+        args.iter()
+            .position(|x| x == name)
+            .and_then(|idx| args.get(idx + 1))
+    };
+
+    if let Some(idx) = args.iter().position(|x| x == "--check") {
+        let failed = args[idx + 1..]
+            .iter()
+            .filter(|path| match scenario::run_file(path) {
+                Ok(()) => {
+                    println!("ok {}", path);
+                    false
+                }
+                Err(err) => {
+                    println!("FAILED {}: {}", path, err);
+                    true
+                }
+            })
+            .count();
+        process::exit(if failed == 0 { 0 } else { 1 });
+    }
+
+    if let Some(out) = arg("--render") {
+        let settings = SynthesizerSettings::load().into_ephemeral();
+        let events = match (arg("--replay"), arg("--events")) {
+            (Some(path), _) => {
+                render::journal_events(settings.clone(), &read_to_string(path).unwrap())
+            }
+            (None, Some(path)) => render::parse_events(&read_to_string(path).unwrap()),
+            (None, None) => {
+                eprintln!("--render requires --replay <journal> or --events <file>");
+                process::exit(1);
+            }
+        };
+        if cfg!(feature = "fluidsynth") && !args.iter().any(|x| x == "--dry-run") {
+            #[cfg(feature = "fluidsynth")]
+            if !render::render(
+                &FluidSynth::offline(
+                    settings.audio(),
+                    settings.recording().dir(),
+                    settings.soundfonts(),
+                    settings.temperaments(),
+                    settings.backing_track(),
+                    out,
+                ),
+                &events,
+            ) {
+                eprintln!("failed to render {}", out);
+                process::exit(1);
+            }
+        } else {
+            println!("# {}", out);
+            print!("{}", render::format_events(&events));
+        }
+        return;
+    }
+
+    let rx = match arg("--replay") {
+        Some(path) => journal::replay(path, !args.iter().any(|x| x == "--fast")).unwrap(),
+        None => start_inputs(DeviceRegistry::load(), DeviceProfile::load()),
+    };
+    let rx = match arg("--record") {
+        Some(path) => journal::record(rx, path).unwrap(),
+        None => rx,
+    };
+    let rx = shutdown::until_signal(rx).unwrap();
+
+    // リプレイは本番の設定ファイルと /boot/recordings を書き換えない
+    let replay = arg("--replay").is_some();
+    let settings = if replay {
+        SynthesizerSettings::load().into_ephemeral()
+    } else {
+        SynthesizerSettings::load()
+    };
+    let dry_run = args.iter().any(|x| x == "--dry-run");
+    let smf = (!dry_run && !replay).then(|| {
+        SmfRecorder::new(
+            settings.recording().dir(),
+            Duration::from_secs(settings.recording().rolling_buffer_minutes() as u64 * 60),
+        )
+    });
+    if cfg!(feature = "fluidsynth") && !dry_run {
+        #[cfg(feature = "fluidsynth")]
+        run(
+            &Router::new(
+                FluidSynth::new(
+                    settings.audio(),
+                    settings.recording().dir(),
+                    settings.soundfonts(),
+                    settings.temperaments(),
+                    settings.backing_track(),
+                ),
+                settings.keyboards(),
+            ),
+            settings,
+            rx,
+            smf,
+        );
+    } else {
+        println!("{:?}", settings.audio());
+        println!("{:?}", settings.recording());
+        println!("backing track: {}", settings.backing_track());
+        for (idx, soundfont) in settings.soundfonts().iter().enumerate() {
+            println!(
+                "soundfont {}: {} (bank offset {})",
+                idx,
+                soundfont.path(),
+                soundfont.bank_offset()
+            );
+        }
+        for (idx, temperament) in settings.temperaments().iter().enumerate() {
+            // C4 からの 1 オクターブの平均律とのずれ
+            let deviations: Vec<_> = Temperament::load(temperament).pitches(0, settings.a4_hz())
+                [60..72]
+                .iter()
+                .zip(60..)
+                .map(|(cents, key)| format!("{:+.1}", cents - key as f64 * 100.0))
+                .collect();
+            println!(
+                "temperament {}: {} [{}]",
+                idx,
+                temperament.name(),
+                deviations.join(" ")
+            );
+        }
+        // --dry-run では外部の音源にも送らない
+        let router = Router::new(
+            Recorder::default(),
+            if dry_run { &[] } else { settings.keyboards() },
+        );
+        run(&router, settings, rx, smf);
+        router
+            .internal()
+            .calls()
+            .iter()
+            .for_each(|call| println!("{:?}", call));
     }
 }
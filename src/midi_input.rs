@@ -0,0 +1,201 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::CString,
+    io,
+    sync::{mpsc, Arc, Mutex, RwLock},
+    thread::{sleep, spawn},
+    time::Duration,
+};
+
+use alsa::{
+    seq::{Addr, ClientIter, EvNote, EventType, PortCap, PortIter, PortSubscribe, PortType},
+    Seq,
+};
+
+use crate::{kmctrler::Event, midi_output::CLIENT_NAME, settings::DeviceRegistry};
+
+/// 自動で接続する MIDI 入力ポート
+///
+/// Midi Through などのソフトウェアポートにキーボード番号を割り当てないよう、
+/// ハードウェアとアプリケーション (仮想ポート) のみを対象とする
+fn is_midi_source(cap: PortCap, port_type: PortType) -> bool {
+    cap.contains(PortCap::READ | PortCap::SUBS_READ)
+        && !cap.contains(PortCap::NO_EXPORT)
+        && port_type.contains(PortType::MIDI_GENERIC)
+        && port_type.intersects(PortType::HARDWARE | PortType::APPLICATION)
+}
+
+fn source_id(seq: &Seq, addr: Addr) -> Option<String> {
+    let client = seq.get_any_client_info(addr.client).ok()?;
+    let port = seq.get_any_port_info(addr).ok()?;
+    Some(format!(
+        "midi:{}:{}",
+        client.get_name().ok()?,
+        port.get_name().ok()?
+    ))
+}
+
+fn connect_sources(
+    dest: Addr,
+    sources: Arc<RwLock<HashMap<Addr, usize>>>,
+    registry: Arc<Mutex<DeviceRegistry>>,
+    tx: mpsc::Sender<(usize, Event)>,
+) {
+    let seq = match Seq::open(None, None, false) {
+        Ok(seq) => seq,
+        Err(err) => {
+            eprintln!("{}", err);
+            return;
+        }
+    };
+    let mut subscribed = HashSet::new();
+    loop {
+        let ports: Vec<_> = ClientIter::new(&seq)
+            // 自分の MIDI 出力を入力として拾うと音が二重になる
+            .filter(|client| {
+                client.get_client() != dest.client && client.get_name() != Ok(CLIENT_NAME)
+            })
+            .flat_map(|client| PortIter::new(&seq, client.get_client()))
+            .filter(|port| is_midi_source(port.get_capability(), port.get_type()))
+            .map(|port| port.addr())
+            .collect();
+        for &addr in &ports {
+            if subscribed.contains(&addr) {
+                continue;
+            }
+            let Ok(subs) = PortSubscribe::empty() else {
+                continue;
+            };
+            subs.set_sender(addr);
+            subs.set_dest(dest);
+            if let Err(err) = seq.subscribe_port(&subs) {
+                eprintln!("{:?}: {}", addr, err);
+            }
+            subscribed.insert(addr);
+        }
+        subscribed.retain(|addr| ports.contains(addr));
+        let mut closed = false;
+        sources.write().unwrap().retain(|addr, &mut idx| {
+            if ports.contains(addr) {
+                return true;
+            }
+            registry.lock().unwrap().release(idx);
+            closed |= tx.send((idx, Event::Disconnect)).is_err();
+            false
+        });
+        if closed {
+            return;
+        }
+        sleep(Duration::from_secs(3));
+    }
+}
+
+/// ALSA シーケンサーのポートを作成し、接続された MIDI キーボードの入力を流す
+///
+/// MIDI キーボードは送信元ポートごとにキーボード番号を割り当てる
+pub fn start_midi_inputs(registry: Arc<Mutex<DeviceRegistry>>, tx: mpsc::Sender<(usize, Event)>) {
+    spawn(move || {
+        let seq = match Seq::open(None, None, false) {
+            Ok(seq) => seq,
+            Err(err) => {
+                eprintln!("{}", err);
+                return;
+            }
+        };
+        let port = seq
+            .set_client_name(&CString::new(CLIENT_NAME).unwrap())
+            .and_then(|_| {
+                seq.create_simple_port(
+                    &CString::new("input").unwrap(),
+                    PortCap::WRITE | PortCap::SUBS_WRITE,
+                    PortType::MIDI_GENERIC | PortType::APPLICATION,
+                )
+            })
+            .and_then(|port| {
+                Ok(Addr {
+                    client: seq.client_id()?,
+                    port,
+                })
+            });
+        let dest = match port {
+            Ok(dest) => dest,
+            Err(err) => {
+                eprintln!("{}", err);
+                return;
+            }
+        };
+
+        let sources = Arc::new(RwLock::new(HashMap::new()));
+        {
+            let sources = sources.clone();
+            let registry = registry.clone();
+            let tx = tx.clone();
+            spawn(move || connect_sources(dest, sources, registry, tx));
+        }
+
+        let mut input = seq.input();
+        loop {
+            let ev = match input.event_input() {
+                Ok(ev) => ev,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    // 受信キューのあふれと割り込みは取りこぼしただけなので読み続ける
+                    match io::Error::from_raw_os_error(err.errno()).kind() {
+                        io::ErrorKind::StorageFull | io::ErrorKind::Interrupted => continue,
+                        _ => return,
+                    }
+                }
+            };
+            let ev_type = ev.get_type();
+            if ev_type != EventType::Noteon && ev_type != EventType::Noteoff {
+                continue;
+            }
+            let addr = ev.get_source();
+            let cached = sources.read().unwrap().get(&addr).copied();
+            let idx = match cached {
+                Some(idx) => idx,
+                None => {
+                    let Some(id) = source_id(&seq, addr) else {
+                        continue;
+                    };
+                    let Some(idx) = registry.lock().unwrap().slot(&id) else {
+                        continue;
+                    };
+                    println!("{} -> {}", id, idx);
+                    sources.write().unwrap().insert(addr, idx);
+                    idx
+                }
+            };
+            let Some(note) = ev.get_data::<EvNote>() else {
+                continue;
+            };
+            let ev = if ev_type == EventType::Noteon && note.velocity > 0 {
+                Event::NoteOn(note.note, note.velocity)
+            } else {
+                Event::NoteOff(note.note)
+            };
+            if tx.send((idx, ev)).is_err() {
+                return;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi_output::{OUTPUT_PORT_CAP, OUTPUT_PORT_TYPE};
+
+    #[test]
+    fn own_output_port_is_not_a_source() {
+        assert!(!is_midi_source(OUTPUT_PORT_CAP, OUTPUT_PORT_TYPE));
+        assert!(is_midi_source(
+            OUTPUT_PORT_CAP.difference(PortCap::NO_EXPORT),
+            OUTPUT_PORT_TYPE
+        ));
+        assert!(!is_midi_source(
+            PortCap::WRITE | PortCap::SUBS_WRITE,
+            OUTPUT_PORT_TYPE
+        ));
+    }
+}
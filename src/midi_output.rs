@@ -0,0 +1,427 @@
+#[cfg(feature = "alsa")]
+use std::ffi::CString;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::Write,
+};
+
+#[cfg(feature = "alsa")]
+use alsa::{
+    seq::{Addr, ClientIter, MidiEvent, PortCap, PortSubscribe, PortType},
+    Seq,
+};
+
+use crate::{
+    settings::{split_channel_of, KeyboardSettings},
+    synth_backend::{
+        SynthBackend, SynthStatus, CC_ALL_NOTES_OFF, CC_BANK_SELECT_LSB, CC_BANK_SELECT_MSB,
+    },
+    synthctrler::Transport,
+};
+
+#[cfg(feature = "alsa")]
+pub const CLIENT_NAME: &str = "km2rasberrypi";
+/// 出力ポートはこちらから接続するだけなので NO_EXPORT にし、MIDI 入力の自動接続に拾わせない
+#[cfg(feature = "alsa")]
+pub const OUTPUT_PORT_CAP: PortCap = PortCap::READ
+    .union(PortCap::SUBS_READ)
+    .union(PortCap::NO_EXPORT);
+#[cfg(feature = "alsa")]
+pub const OUTPUT_PORT_TYPE: PortType = PortType::MIDI_GENERIC.union(PortType::APPLICATION);
+const CC_DATA_ENTRY_MSB: u8 = 6;
+const CC_DATA_ENTRY_LSB: u8 = 38;
+const CC_RPN_LSB: u8 = 100;
+const CC_RPN_MSB: u8 = 101;
+/// RPN 0x0001 チャンネル・ファインチューニング (±100 セント)
+const RPN_FINE_TUNING: u8 = 1;
+const RPN_NULL: u8 = 127;
+
+enum Sink {
+    #[cfg(feature = "alsa")]
+    Seq {
+        seq: Seq,
+        port: i32,
+        encoder: RefCell<MidiEvent>,
+    },
+    Raw(RefCell<File>),
+}
+
+/// 外部の音源へ MIDI メッセージを送るバックエンド
+///
+/// 送り先は raw MIDI デバイス (/dev/snd/midiC1D0 など) か
+/// ALSA シーケンサーのポート (seq:<クライアント番号か名前>:<ポート番号>)。
+/// シーケンサーは alsa フィーチャーが有効なときだけ使える
+pub struct MidiOutput {
+    sink: Sink,
+}
+
+#[cfg(feature = "alsa")]
+fn find_client(seq: &Seq, client: &str) -> Option<i32> {
+    if let Ok(client) = client.parse() {
+        return Some(client);
+    }
+    ClientIter::new(seq)
+        .find(|x| x.get_name().is_ok_and(|name| name == client))
+        .map(|x| x.get_client())
+}
+
+impl MidiOutput {
+    pub fn open(dest: &str) -> Result<Self, String> {
+        let Some(dest) = dest.strip_prefix("seq:") else {
+            let file = OpenOptions::new()
+                .write(true)
+                .open(dest)
+                .map_err(|err| format!("{}: {}", dest, err))?;
+            return Ok(Self {
+                sink: Sink::Raw(RefCell::new(file)),
+            });
+        };
+        Self::open_seq(dest)
+    }
+
+    #[cfg(not(feature = "alsa"))]
+    fn open_seq(dest: &str) -> Result<Self, String> {
+        Err(format!("seq:{}: built without the alsa feature", dest))
+    }
+
+    #[cfg(feature = "alsa")]
+    fn open_seq(dest: &str) -> Result<Self, String> {
+        let (client, port) = dest.rsplit_once(':').unwrap_or((dest, "0"));
+        let seq = Seq::open(None, None, false).map_err(|err| err.to_string())?;
+        seq.set_client_name(&CString::new(CLIENT_NAME).unwrap())
+            .map_err(|err| err.to_string())?;
+        let sender = Addr {
+            client: seq.client_id().map_err(|err| err.to_string())?,
+            port: seq
+                .create_simple_port(
+                    &CString::new("output").unwrap(),
+                    OUTPUT_PORT_CAP,
+                    OUTPUT_PORT_TYPE,
+                )
+                .map_err(|err| err.to_string())?,
+        };
+        let dest_addr = Addr {
+            client: find_client(&seq, client).ok_or_else(|| format!("{}: not found", dest))?,
+            port: port
+                .parse()
+                .map_err(|_| format!("{}: invalid port", dest))?,
+        };
+        let subs = PortSubscribe::empty().map_err(|err| err.to_string())?;
+        subs.set_sender(sender);
+        subs.set_dest(dest_addr);
+        seq.subscribe_port(&subs)
+            .map_err(|err| format!("{}: {}", dest, err))?;
+        Ok(Self {
+            sink: Sink::Seq {
+                seq,
+                port: sender.port,
+                encoder: RefCell::new(MidiEvent::new(16).map_err(|err| err.to_string())?),
+            },
+        })
+    }
+
+    fn send(&self, message: &[u8]) -> bool {
+        match &self.sink {
+            #[cfg(feature = "alsa")]
+            Sink::Seq { seq, port, encoder } => {
+                let mut encoder = encoder.borrow_mut();
+                let Ok((_, Some(mut ev))) = encoder.encode(message) else {
+                    return false;
+                };
+                ev.set_source(*port);
+                ev.set_subs();
+                ev.set_direct();
+                seq.event_output_direct(&mut ev).is_ok()
+            }
+            Sink::Raw(file) => file.borrow_mut().write_all(message).is_ok(),
+        }
+    }
+}
+
+impl SynthBackend for MidiOutput {
+    fn noteon(&self, chan: u8, key: u8, vel: u8) -> bool {
+        self.send(&[0x90 | chan, key, vel])
+    }
+
+    fn noteoff(&self, chan: u8, key: u8) -> bool {
+        self.send(&[0x80 | chan, key, 0])
+    }
+
+    fn all_notes_off(&self, chan: u8) -> bool {
+        self.cc(chan, CC_ALL_NOTES_OFF, 0)
+    }
+
+    fn program_change(&self, chan: u8, program: u8) -> bool {
+        self.send(&[0xC0 | chan, program])
+    }
+
+    /// soundfont の指定は外部の音源には無いので無視する
+    fn bank_select(&self, chan: u8, _soundfont: Option<u8>, bank: u16) -> bool {
+        self.cc(chan, CC_BANK_SELECT_MSB, (bank >> 7) as u8 & 0x7F)
+            && self.cc(chan, CC_BANK_SELECT_LSB, bank as u8 & 0x7F)
+    }
+
+    /// チャンネル・ファインチューニングで送るので、ずれは ±100 セントまで
+    fn tuning(&self, chan: u8, tuning: i32, fine_tune: i32) -> bool {
+        let cents = tuning as f64 * 100.0 / 12.0 + fine_tune as f64;
+        let value = (8192.0 + cents * 8192.0 / 100.0)
+            .round()
+            .clamp(0.0, 16383.0) as u16;
+        self.cc(chan, CC_RPN_MSB, 0)
+            && self.cc(chan, CC_RPN_LSB, RPN_FINE_TUNING)
+            && self.cc(chan, CC_DATA_ENTRY_MSB, (value >> 7) as u8)
+            && self.cc(chan, CC_DATA_ENTRY_LSB, value as u8 & 0x7F)
+            && self.cc(chan, CC_RPN_MSB, RPN_NULL)
+            && self.cc(chan, CC_RPN_LSB, RPN_NULL)
+    }
+
+    /// 鍵盤ごとの音程は外部の音源には送らない
+    fn temperament(&self, _chan: u8, _temperament: u8, _root: u8) -> bool {
+        true
+    }
+
+    /// 外部の音源の基準ピッチは音源の側で合わせる
+    fn concert_pitch(&self, _a4_hz: u16) -> bool {
+        true
+    }
+
+    fn recording(&self, _on: bool) -> bool {
+        true
+    }
+
+    fn backing_track(&self, _transport: Transport) -> bool {
+        true
+    }
+
+    fn cc(&self, chan: u8, ctrl: u8, value: u8) -> bool {
+        self.send(&[0xB0 | chan, ctrl, value])
+    }
+}
+
+/// キーボードごとの設定に従って、内蔵の音源と外部の MIDI 出力へ振り分ける
+///
+/// キーボードの左側のチャンネルも、分けているかどうかによらず同じ送り先にする。
+/// チャンネルを持たないイベントと、設定の無いチャンネルは内蔵の音源のみに送る
+pub struct Router<B: SynthBackend, O: SynthBackend = MidiOutput> {
+    internal: B,
+    outputs: Vec<O>,
+    /// チャンネルごとの (内蔵の音源に送るか, outputs の番号)
+    routes: HashMap<u8, (bool, Option<usize>)>,
+}
+
+impl<B: SynthBackend> Router<B> {
+    pub fn new(internal: B, keyboards: &[KeyboardSettings]) -> Self {
+        Self::with_outputs(internal, keyboards, MidiOutput::open)
+    }
+}
+
+impl<B: SynthBackend, O: SynthBackend> Router<B, O> {
+    /// 設定の midi_output を open で開く。同じ送り先は 1 度だけ開く
+    fn with_outputs(
+        internal: B,
+        keyboards: &[KeyboardSettings],
+        open: impl Fn(&str) -> Result<O, String>,
+    ) -> Self {
+        let mut dests: Vec<&str> = Vec::new();
+        let mut outputs = Vec::new();
+        let mut routes = HashMap::new();
+        for (chan, keyboard) in keyboards.iter().enumerate() {
+            let output = keyboard.midi_output().as_deref().and_then(|dest| {
+                if let Some(idx) = dests.iter().position(|x| *x == dest) {
+                    return Some(idx);
+                }
+                match open(dest) {
+                    Ok(output) => {
+                        println!("midi output: {}", dest);
+                        dests.push(dest);
+                        outputs.push(output);
+                        Some(outputs.len() - 1)
+                    }
+                    Err(err) => {
+                        eprintln!("{}", err);
+                        None
+                    }
+                }
+            });
+            routes.insert(chan as u8, (keyboard.internal_synth(), output));
+            if let Some(split) = split_channel_of(chan as u8) {
+                routes.insert(split, (keyboard.internal_synth(), output));
+            }
+        }
+        Self {
+            internal,
+            outputs,
+            routes,
+        }
+    }
+
+    pub fn internal(&self) -> &B {
+        &self.internal
+    }
+
+    fn route(&self, chan: u8, f: impl Fn(&dyn SynthBackend) -> bool) -> bool {
+        let (internal, output) = self.routes.get(&chan).copied().unwrap_or((true, None));
+        let output = output.is_none_or(|idx| f(&self.outputs[idx]));
+        let internal = !internal || f(&self.internal);
+        output && internal
+    }
+}
+
+impl<B: SynthBackend, O: SynthBackend> SynthBackend for Router<B, O> {
+    fn noteon(&self, chan: u8, key: u8, vel: u8) -> bool {
+        self.route(chan, |x| x.noteon(chan, key, vel))
+    }
+
+    fn noteoff(&self, chan: u8, key: u8) -> bool {
+        self.route(chan, |x| x.noteoff(chan, key))
+    }
+
+    fn all_notes_off(&self, chan: u8) -> bool {
+        self.route(chan, |x| x.all_notes_off(chan))
+    }
+
+    fn program_change(&self, chan: u8, program: u8) -> bool {
+        self.route(chan, |x| x.program_change(chan, program))
+    }
+
+    fn bank_select(&self, chan: u8, soundfont: Option<u8>, bank: u16) -> bool {
+        self.route(chan, |x| x.bank_select(chan, soundfont, bank))
+    }
+
+    fn tuning(&self, chan: u8, tuning: i32, fine_tune: i32) -> bool {
+        self.route(chan, |x| x.tuning(chan, tuning, fine_tune))
+    }
+
+    fn temperament(&self, chan: u8, temperament: u8, root: u8) -> bool {
+        self.route(chan, |x| x.temperament(chan, temperament, root))
+    }
+
+    fn concert_pitch(&self, a4_hz: u16) -> bool {
+        self.internal.concert_pitch(a4_hz)
+    }
+
+    fn recording(&self, on: bool) -> bool {
+        self.internal.recording(on)
+    }
+
+    fn backing_track(&self, transport: Transport) -> bool {
+        self.internal.backing_track(transport)
+    }
+
+    fn cc(&self, chan: u8, ctrl: u8, value: u8) -> bool {
+        self.route(chan, |x| x.cc(chan, ctrl, value))
+    }
+
+    fn status(&self) -> SynthStatus {
+        self.internal.status()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env::temp_dir,
+        fs::{read, remove_file},
+        process,
+    };
+
+    use super::*;
+    use crate::synth_backend::{Call, Recorder};
+
+    #[test]
+    fn midi_output_messages() {
+        let path = temp_dir().join(format!("km2rasberrypi-midi-{}", process::id()));
+        File::create(&path).unwrap();
+        let output = MidiOutput::open(path.to_str().unwrap()).unwrap();
+        assert!(output.noteon(2, 60, 100));
+        assert!(output.noteoff(2, 60));
+        assert!(output.program_change(2, 5));
+        assert!(output.bank_select(2, Some(1), 129));
+        assert!(output.all_notes_off(2));
+        drop(output);
+        let bytes = read(&path).unwrap();
+        remove_file(&path).unwrap();
+        assert_eq!(
+            bytes,
+            [
+                0x92, 60, 100, // ノートオン
+                0x82, 60, 0, // ノートオフ
+                0xC2, 5, // プログラムチェンジ
+                0xB2, 0, 1, 0xB2, 32, 1, // バンクセレクト 129 = 1 * 128 + 1
+                0xB2, 123, 0, // オールノートオフ
+            ]
+        );
+    }
+
+    /// チャンネル・ファインチューニングの値。8192 が 0 セントで ±100 セントが ±8192
+    #[test]
+    fn tuning_sends_fine_tuning_rpn() {
+        for (tuning, fine_tune, msb, lsb) in [
+            (0, 0, 0x40, 0x00),
+            // 半音の 1/12 が 6 つで +50 セント = 12288
+            (6, 0, 0x60, 0x00),
+            (0, -50, 0x20, 0x00),
+            // +1 セント = 8192 + 81.92 を丸めて 8274
+            (0, 1, 0x40, 0x52),
+            (0, -100, 0x00, 0x00),
+            // +100 セントは 16384 になるので上限の 16383 に収める
+            (0, 100, 0x7F, 0x7F),
+        ] {
+            let path = temp_dir().join(format!(
+                "km2rasberrypi-rpn-{}-{}-{}",
+                process::id(),
+                tuning,
+                fine_tune
+            ));
+            File::create(&path).unwrap();
+            let output = MidiOutput::open(path.to_str().unwrap()).unwrap();
+            assert!(output.tuning(3, tuning, fine_tune));
+            drop(output);
+            let bytes = read(&path).unwrap();
+            remove_file(&path).unwrap();
+            assert_eq!(
+                bytes,
+                [
+                    0xB3, 101, 0, 0xB3, 100, 1, // RPN 0x0001
+                    0xB3, 6, msb, 0xB3, 38, lsb, // データエントリー
+                    0xB3, 101, 127, 0xB3, 100, 127, // RPN null
+                ],
+                "tuning {} fine_tune {}",
+                tuning,
+                fine_tune
+            );
+        }
+    }
+
+    #[test]
+    fn router_skips_internal_synth_when_disabled() {
+        let mut keyboards = vec![KeyboardSettings::default(), KeyboardSettings::default()];
+        keyboards[1].set_midi_output(Some("external".to_owned()));
+        keyboards[1].set_internal_synth(false);
+        let router = Router::with_outputs(Recorder::default(), &keyboards, |dest| {
+            assert_eq!(dest, "external");
+            Ok(Recorder::default())
+        });
+        assert!(router.noteon(0, 60, 100));
+        assert!(router.noteon(1, 62, 90));
+        // キーボード 1 の左側のチャンネル
+        assert!(router.program_change(14, 7));
+        // 設定の無いチャンネルと、チャンネルを持たないイベントは内蔵の音源のみ
+        assert!(router.cc(5, 64, 127));
+        assert!(router.recording(true));
+        assert_eq!(
+            router.internal().calls(),
+            [
+                Call::Noteon(0, 60, 100),
+                Call::Cc(5, 64, 127),
+                Call::Recording(true),
+            ]
+        );
+        assert_eq!(
+            router.outputs[0].calls(),
+            [Call::Noteon(1, 62, 90), Call::ProgramChange(14, 7)]
+        );
+    }
+}
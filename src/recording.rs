@@ -0,0 +1,107 @@
+use std::{
+    fs::create_dir_all,
+    io,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, SyncSender},
+        Arc,
+    },
+    thread::{spawn, JoinHandle},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use hound::{SampleFormat, WavSpec, WavWriter};
+
+/// オーディオスレッドとの間で使い回すバッファの数
+const BUFFERS: usize = 64;
+
+/// オーディオスレッド側の送り口
+///
+/// 書き出し済みのバッファを受け取って詰め直すので、送るたびにメモリを確保しない
+pub struct Buffers {
+    tx: SyncSender<Vec<f32>>,
+    free: Receiver<Vec<f32>>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl Buffers {
+    /// 左右のサンプルを交互に並べて送る。空きのバッファが無ければ捨てて数える
+    pub fn send(&self, left: &[f32], right: &[f32]) {
+        let Ok(mut buffer) = self.free.try_recv() else {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        };
+        buffer.clear();
+        buffer.extend(left.iter().zip(right).flat_map(|(&l, &r)| [l, r]));
+        if self.tx.try_send(buffer).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// オーディオスレッドから受け取ったステレオの音声を WAV ファイルに書き出す
+///
+/// ファイル名は録音開始時の UNIX 時刻
+pub struct Recording {
+    handle: JoinHandle<()>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl Recording {
+    /// frames は 1 回のコールバックで受け取るフレーム数の目安
+    pub fn start(dir: &str, sample_rate: u32, frames: usize) -> io::Result<(Self, Buffers)> {
+        create_dir_all(dir)?;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let path = Path::new(dir).join(format!("{}.wav", timestamp));
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let mut writer = WavWriter::create(&path, spec).map_err(io::Error::other)?;
+        println!("recording: {}", path.display());
+        let (tx, rx) = mpsc::sync_channel::<Vec<f32>>(BUFFERS);
+        let (free_tx, free) = mpsc::sync_channel(BUFFERS);
+        for _ in 0..BUFFERS {
+            free_tx.send(Vec::with_capacity(frames * 2)).unwrap();
+        }
+        let handle = spawn(move || {
+            for samples in rx {
+                for &sample in &samples {
+                    let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+                    if let Err(err) = writer.write_sample(sample) {
+                        eprintln!("{}", err);
+                        return;
+                    }
+                }
+                let _ = free_tx.try_send(samples);
+            }
+            if let Err(err) = writer.finalize() {
+                eprintln!("{}", err);
+            }
+        });
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let buffers = Buffers {
+            tx,
+            free,
+            dropped: dropped.clone(),
+        };
+        Ok((Self { handle, dropped }, buffers))
+    }
+
+    /// Buffers が閉じられていれば、書き出しを終えるまで待つ
+    pub fn stop(self) {
+        if self.handle.join().is_err() {
+            eprintln!("recording thread panicked");
+        }
+        let dropped = self.dropped.load(Ordering::Relaxed);
+        if dropped > 0 {
+            eprintln!("recording: {} periods dropped", dropped);
+        }
+    }
+}
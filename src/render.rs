@@ -0,0 +1,150 @@
+use std::sync::mpsc;
+
+#[cfg(feature = "fluidsynth")]
+use crate::fluid_synth::FluidSynth;
+use crate::{
+    init, journal,
+    settings::SynthesizerSettings,
+    synth_backend::{Recorder, SynthBackend},
+    synthctrler::{Event, Output, SynthCtrler, Transport},
+};
+
+/// 最後のイベントのあとに書き出す余韻 (ミリ秒)
+#[cfg(feature = "fluidsynth")]
+const TAIL: u64 = 2000;
+
+/// ジャーナルの入力を SynthCtrler に通し、経過ミリ秒付きの synthctrler::Event にする
+///
+/// 操作方式が出力の合間に入れる待ち時間は、実際には待たずに時刻へ足す。
+/// 実際に演奏したときと同じく後の入力も遅らせる。
+/// 録音などの切り替えは、全ての処理が成功したものとして決める
+pub fn journal_events(mut settings: SynthesizerSettings, journal: &str) -> Vec<(u64, Event)> {
+    let mut events: Vec<_> = init(&mut settings).into_iter().map(|ev| (0, ev)).collect();
+    let (tx, rx) = mpsc::channel();
+    let mut ctrler = SynthCtrler::new(settings, rx);
+    let recorder = Recorder::default();
+    let mut now = 0;
+    for (timestamp, idx, ev) in journal::parse(journal) {
+        now = now.max(timestamp);
+        tx.send((idx, ev)).unwrap();
+        while let Ok((delay, Output { event: ev, .. })) = ctrler.try_recv(&recorder) {
+            now += delay.as_millis() as u64;
+            events.push((now, ev));
+            ctrler.processed(ev, recorder.process(ev));
+        }
+    }
+    events
+}
+
+/// synthctrler::Event を Debug 表記から読む。空白は無視する
+fn parse_event(text: &str) -> Option<Event> {
+    let text: String = text.split_whitespace().collect();
+    match text.as_str() {
+        "RecordingOn" => return Some(Event::RecordingOn),
+        "RecordingOff" => return Some(Event::RecordingOff),
+        "SplitMidiFile" => return Some(Event::SplitMidiFile),
+        "DumpRollingBuffer" => return Some(Event::DumpRollingBuffer),
+        "BackingTrack(Start)" => return Some(Event::BackingTrack(Transport::Start)),
+        "BackingTrack(Stop)" => return Some(Event::BackingTrack(Transport::Stop)),
+        "BackingTrack(Rewind)" => return Some(Event::BackingTrack(Transport::Rewind)),
+        _ => {}
+    }
+    let (name, args) = text.strip_suffix(')')?.split_once('(')?;
+    let args: Vec<_> = args.split(',').collect();
+    let arg = |idx: usize| args.get(idx)?.parse::<u8>().ok();
+    match (name, args.len()) {
+        ("Noteon", 3) => Some(Event::Noteon(arg(0)?, arg(1)?, arg(2)?)),
+        ("Noteoff", 2) => Some(Event::Noteoff(arg(0)?, arg(1)?)),
+        ("AllNotesOff", 1) => Some(Event::AllNotesOff(arg(0)?)),
+        ("ProgramChange", 2) => Some(Event::ProgramChange(arg(0)?, arg(1)?)),
+        ("BankSelect", 3) => {
+            let soundfont = match args[1] {
+                "None" => None,
+                x => Some(x.strip_prefix("Some(")?.strip_suffix(')')?.parse().ok()?),
+            };
+            Some(Event::BankSelect(arg(0)?, soundfont, args[2].parse().ok()?))
+        }
+        ("Tuning", 3) => Some(Event::Tuning(
+            arg(0)?,
+            args[1].parse().ok()?,
+            args[2].parse().ok()?,
+        )),
+        ("Temperament", 3) => Some(Event::Temperament(arg(0)?, arg(1)?, arg(2)?)),
+        ("ConcertPitch", 1) => Some(Event::ConcertPitch(args[0].parse().ok()?)),
+        ("HoldOn", 1) => Some(Event::HoldOn(arg(0)?)),
+        ("HoldOff", 1) => Some(Event::HoldOff(arg(0)?)),
+        ("ModulationOn", 1) => Some(Event::ModulationOn(arg(0)?)),
+        ("ModulationOff", 1) => Some(Event::ModulationOff(arg(0)?)),
+        ("ReverbOn", 1) => Some(Event::ReverbOn(arg(0)?)),
+        ("ReverbOff", 1) => Some(Event::ReverbOff(arg(0)?)),
+        ("ChorusOn", 1) => Some(Event::ChorusOn(arg(0)?)),
+        ("ChorusOff", 1) => Some(Event::ChorusOff(arg(0)?)),
+        _ => None,
+    }
+}
+
+/// 1 行に 1 つ (経過ミリ秒, synthctrler::Event) を並べたファイルを読む
+///
+/// 空行と # から始まる行は読み飛ばす。format_events の出力をそのまま読める
+pub fn parse_events(text: &str) -> Vec<(u64, Event)> {
+    text.lines()
+        .enumerate()
+        .filter_map(|(no, line)| {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                return None;
+            }
+            let entry = line
+                .split_once(char::is_whitespace)
+                .and_then(|(timestamp, ev)| Some((timestamp.parse().ok()?, parse_event(ev)?)));
+            if entry.is_none() {
+                eprintln!("events:{}: invalid line: {}", no + 1, line);
+            }
+            entry
+        })
+        .collect()
+}
+
+pub fn format_events(events: &[(u64, Event)]) -> String {
+    events
+        .iter()
+        .map(|(timestamp, ev)| format!("{}\t{:?}\n", timestamp, ev))
+        .collect()
+}
+
+/// イベントを経過時間どおりに鳴らしながらファイルへ書き出す
+#[cfg(feature = "fluidsynth")]
+pub fn render(synth: &FluidSynth, events: &[(u64, Event)]) -> bool {
+    for &(timestamp, ev) in events {
+        if !synth.render_until(timestamp) {
+            return false;
+        }
+        if !synth.process(ev) {
+            eprintln!("{}: failed to process {:?}", timestamp, ev);
+        }
+    }
+    synth.render_until(events.last().map_or(0, |&(timestamp, _)| timestamp) + TAIL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn journal_events_add_cue_delays_without_waiting() {
+        let journal = "10\t0\tpress select\n20\t0\tpress start\n50\t0\tpress key 0\n";
+        let events = journal_events(SynthesizerSettings::ephemeral(), journal);
+        assert_eq!(
+            events,
+            [
+                (0, Event::ConcertPitch(440)),
+                (10, Event::ModulationOn(0)),
+                (20, Event::Noteon(9, 42, 127)),
+                (120, Event::Noteoff(9, 42)),
+                (120, Event::Noteon(9, 42, 127)),
+                (220, Event::Noteoff(9, 42)),
+                (220, Event::Noteon(0, 60, 100)),
+            ]
+        );
+    }
+}
@@ -0,0 +1,226 @@
+use std::{fs::read_to_string, sync::mpsc};
+
+use crate::{
+    journal::parse_event,
+    settings::{ControlScheme, KeyboardSettings, SynthesizerSettings},
+    synth_backend::{Recorder, SynthBackend},
+    synthctrler::{Output, SynthCtrler},
+};
+
+fn get(keyboard: &KeyboardSettings, field: &[&str]) -> Option<String> {
+    match field {
+        ["octave"] => Some(keyboard.octave().to_string()),
+        ["transpose"] => Some(keyboard.transpose().to_string()),
+        ["program_no"] => Some(keyboard.program_no().to_string()),
+        ["split_point"] => Some(
+            keyboard
+                .split_point()
+                .map_or("none".to_owned(), |x| x.to_string()),
+        ),
+        ["split_program_no"] => Some(keyboard.split_program_no().to_string()),
+        ["split_octave"] => Some(keyboard.split_octave().to_string()),
+        ["reverb"] => Some(keyboard.reverb().to_string()),
+        ["chorus"] => Some(keyboard.chorus().to_string()),
+        ["tuning"] => Some(keyboard.tuning().to_string()),
+        ["fine_tune"] => Some(keyboard.fine_tune().to_string()),
+        ["temperament"] => Some(keyboard.temperament().to_string()),
+        ["temperament_root"] => Some(keyboard.temperament_root().to_string()),
+        ["control_scheme"] => Some(
+            keyboard
+                .control_scheme()
+                .map_or("default", |x| x.name())
+                .to_owned(),
+        ),
+        ["velocity_per_program", program_no] => keyboard
+            .velocity_per_program()
+            .get(program_no.parse::<usize>().ok()?)
+            .map(|x| x.to_string()),
+        _ => None,
+    }
+}
+
+fn set(keyboard: &mut KeyboardSettings, field: &[&str], value: &str) -> Option<()> {
+    match field {
+        ["octave"] => {
+            keyboard.set_octave(value.parse().ok()?);
+        }
+        ["transpose"] => {
+            keyboard.set_transpose(value.parse().ok()?);
+        }
+        ["program_no"] => {
+            keyboard.set_program_no(value.parse().ok()?);
+        }
+        ["split_point"] => {
+            keyboard.set_split_point(match value {
+                "none" => None,
+                value => Some(value.parse().ok()?),
+            });
+        }
+        ["split_program_no"] => {
+            keyboard.set_split_program_no(value.parse().ok()?);
+        }
+        ["split_octave"] => {
+            keyboard.set_split_octave(value.parse().ok()?);
+        }
+        ["reverb"] => {
+            keyboard.set_reverb(value.parse().ok()?);
+        }
+        ["chorus"] => {
+            keyboard.set_chorus(value.parse().ok()?);
+        }
+        ["tuning"] => {
+            keyboard.set_tuning(value.parse().ok()?);
+        }
+        ["fine_tune"] => {
+            keyboard.set_fine_tune(value.parse().ok()?);
+        }
+        ["temperament"] => {
+            keyboard.set_temperament(value.parse().ok()?);
+        }
+        ["temperament_root"] => {
+            keyboard.set_temperament_root(value.parse().ok()?);
+        }
+        ["control_scheme"] => {
+            keyboard.set_control_scheme(Some(ControlScheme::parse(value)?));
+        }
+        ["velocity_per_program", program_no] => {
+            *keyboard
+                .velocity_per_program_mut()
+                .get_mut(program_no.parse::<usize>().ok()?)? = value.parse().ok()?;
+        }
+        _ => return None,
+    }
+    Some(())
+}
+
+fn drain(ctrler: &mut SynthCtrler, recorder: &Recorder) -> Result<(), String> {
+    match ctrler.try_recv(recorder) {
+        Ok((_, output)) => Err(format!("unexpected {:?}", output.event)),
+        Err(_) => Ok(()),
+    }
+}
+
+/// 入力の台本を SynthCtrler に流し、出力と設定を検証する
+///
+/// 先頭の行で制御方式と初期設定を指定する
+///   scheme v1 | v2 | v3
+///   set <キーボード番号> <項目> <値>
+/// 以降は入力と検証を並べる
+///   <キーボード番号> <ジャーナルと同じ形式のイベント>
+///   expect <synthctrler::Event>
+///   reject <synthctrler::Event>
+///   assert <キーボード番号> <項目> <値>
+/// expect した出力は Recorder で処理し、reject は処理に失敗したことにする。
+/// assert の前と台本の最後で、expect されていない出力が残っていれば失敗とする
+pub fn run(script: &str) -> Result<(), String> {
+    let lines: Vec<(usize, Vec<&str>)> = script
+        .lines()
+        .enumerate()
+        .filter_map(|(no, line)| {
+            let words: Vec<_> = line.split('#').next().unwrap().split_whitespace().collect();
+            (!words.is_empty()).then_some((no + 1, words))
+        })
+        .collect();
+    let header_len = lines
+        .iter()
+        .take_while(|(_, words)| matches!(words[0], "scheme" | "set"))
+        .count();
+
+    let mut settings = SynthesizerSettings::ephemeral();
+    for (no, words) in &lines[..header_len] {
+        match words.as_slice() {
+            ["scheme", name] => {
+                let control_scheme = ControlScheme::parse(name)
+                    .ok_or_else(|| format!("line {no}: unknown scheme: {name}"))?;
+                settings.set_default_control_scheme(control_scheme);
+            }
+            ["set", idx, field @ .., value] => {
+                let idx = idx
+                    .parse()
+                    .map_err(|_| format!("line {no}: invalid keyboard"))?;
+                set(settings.get_or_create_keyboard_mut(idx), field, value)
+                    .ok_or_else(|| format!("line {no}: invalid setting"))?;
+            }
+            _ => return Err(format!("line {no}: invalid header")),
+        }
+    }
+
+    let (tx, rx) = mpsc::channel();
+    let mut ctrler = SynthCtrler::new(settings, rx);
+    let recorder = Recorder::default();
+    for (no, words) in &lines[header_len..] {
+        match words.as_slice() {
+            [verb @ ("expect" | "reject"), expected @ ..] => {
+                let expected = expected.concat();
+                let (_, Output { event: ev, .. }) = ctrler
+                    .try_recv(&recorder)
+                    .map_err(|_| format!("line {no}: expected {expected} but got nothing"))?;
+                let actual = format!("{:?}", ev).replace(' ', "");
+                if actual != expected {
+                    return Err(format!("line {no}: expected {expected} but got {actual}"));
+                }
+                let ok = *verb == "expect" && recorder.process(ev);
+                ctrler.processed(ev, ok);
+            }
+            ["assert", idx, field @ .., expected] => {
+                drain(&mut ctrler, &recorder).map_err(|err| format!("line {no}: {err}"))?;
+                let idx = idx
+                    .parse::<usize>()
+                    .map_err(|_| format!("line {no}: invalid keyboard"))?;
+                let keyboard = ctrler
+                    .settings()
+                    .keyboards()
+                    .get(idx)
+                    .cloned()
+                    .unwrap_or_default();
+                let actual =
+                    get(&keyboard, field).ok_or_else(|| format!("line {no}: invalid setting"))?;
+                if actual != *expected {
+                    return Err(format!(
+                        "line {no}: expected {} = {expected} but got {actual}",
+                        field.join(" ")
+                    ));
+                }
+            }
+            [idx, ev @ ..] => {
+                let idx = idx
+                    .parse()
+                    .map_err(|_| format!("line {no}: invalid keyboard"))?;
+                let ev = parse_event(ev).ok_or_else(|| format!("line {no}: invalid event"))?;
+                tx.send((idx, ev)).unwrap();
+            }
+            [] => {}
+        }
+    }
+    drain(&mut ctrler, &recorder).map_err(|err| format!("end of script: {err}"))
+}
+
+pub fn run_file(path: &str) -> Result<(), String> {
+    run(&read_to_string(path).map_err(|err| err.to_string())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::read_dir;
+
+    use super::run_file;
+
+    #[test]
+    fn scenarios() {
+        let mut paths: Vec<_> = read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/scenarios"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|x| x == "txt"))
+            .collect();
+        paths.sort();
+        assert!(!paths.is_empty());
+        let failures: Vec<_> = paths
+            .iter()
+            .filter_map(|path| {
+                let path = path.to_str().unwrap();
+                run_file(path).err().map(|err| format!("{}: {}", path, err))
+            })
+            .collect();
+        assert!(failures.is_empty(), "{}", failures.join("\n"));
+    }
+}
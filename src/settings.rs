@@ -1,64 +1,548 @@
 use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
     fs::{self, read_to_string},
+    ops::RangeInclusive,
+    path::PathBuf,
     sync::{
         atomic::{AtomicU64, Ordering},
-        Arc,
+        Arc, Mutex,
     },
     thread::{sleep, spawn},
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use getset::{CopyGetters, Getters, MutGetters, Setters};
-use toml_edit::{ArrayOfTables, Document, Item, Table, Value};
+use toml_edit::{ArrayOfTables, Document, InlineTable, Item, Table, Value};
 
-const PATH: &str = "/boot/km2rasberrypi.toml";
+use crate::{
+    kmctrler::{Input, KeyMap},
+    temperament::PRESETS,
+};
+
+const DEFAULT_VELOCITY: u8 = 100;
+const DEFAULT_SOUNDFONT: &str = "/usr/share/sounds/sf2/FluidR3_GM.sf2";
+const DEFAULT_BACKING_TRACK: &str = "backing.mid";
+const DEFAULT_A4_HZ: u16 = 440;
+/// キーボードに割り当てるチャンネル (0..=8) の数。9 は打楽器、10..=15 は鍵盤を分けた左側に使う
+pub const KEYBOARD_SLOTS: usize = 9;
+/// 鍵盤を分けられるキーボードの数。キーボード idx の左側は 15 - idx のチャンネルで鳴らす
+const SPLIT_KEYBOARDS: u8 = 6;
+/// オクターブと左側のオクターブの上限
+pub const MAX_OCTAVE: u8 = 9;
+/// 移調の上限 (半音)
+pub const TRANSPOSE_LIMIT: i8 = 12;
+/// 微調整の上限 (セント)
+pub const FINE_TUNE_LIMIT: i32 = 50;
+/// 基準ピッチとして受け付ける範囲
+pub const A4_HZ_RANGE: RangeInclusive<u16> = 380..=480;
+
+static WRITE_LOCK: Mutex<()> = Mutex::new(());
+
+#[cfg(not(test))]
+fn settings_path() -> PathBuf {
+    PathBuf::from("/boot/km2rasberrypi.toml")
+}
+
+/// テストでは /boot を書き換えないよう、スレッドごとの一時ファイルを使う
+#[cfg(test)]
+fn settings_path() -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "km2rasberrypi-{}-{:?}.toml",
+        std::process::id(),
+        std::thread::current().id()
+    ))
+}
 
 fn read() -> Document {
-    read_to_string(PATH)
+    read_to_string(settings_path())
         .unwrap_or_default()
         .parse()
         .unwrap_or_default()
 }
 
+fn write(doc: &Document) {
+    if let Err(err) = fs::write(settings_path(), doc.to_string()) {
+        eprintln!("{}", err);
+    }
+}
+
 fn integer(table: &Table, key: &str) -> Option<i64> {
     table.get(key).and_then(|x| x.as_integer())
 }
 
+/// 範囲外の値は端に寄せる
+fn clamped(table: &Table, key: &str, range: RangeInclusive<i64>, default: i64) -> i64 {
+    integer(table, key)
+        .unwrap_or(default)
+        .clamp(*range.start(), *range.end())
+}
+
 fn bool(table: &Table, key: &str) -> Option<bool> {
     table.get(key).and_then(|x| x.as_bool())
 }
 
+fn string<'a>(table: &'a Table, key: &str) -> Option<&'a str> {
+    table.get(key).and_then(|x| x.as_str())
+}
+
+fn number(table: &Table, key: &str) -> Option<f64> {
+    let item = table.get(key)?;
+    item.as_float()
+        .or_else(|| item.as_integer().map(|x| x as f64))
+}
+
 fn put(doc: &mut Table, key: &str, value: impl Into<Value>) {
-    let value = value.into();
+    put_item(doc, key, Item::Value(value.into()));
+}
+
+fn put_item(doc: &mut Table, key: &str, value: Item) {
     if let Some(item) = doc.get_mut(key) {
-        *item = Item::Value(value);
+        *item = value;
     } else {
-        let _ = doc.insert(key, Item::Value(value));
+        let _ = doc.insert(key, value);
     }
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum ControlScheme {
+    V1,
+    V2,
+    #[default]
+    V3,
+}
+
+impl ControlScheme {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "v1" => Some(Self::V1),
+            "v2" => Some(Self::V2),
+            "v3" => Some(Self::V3),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::V1 => "v1",
+            Self::V2 => "v2",
+            Self::V3 => "v3",
+        }
+    }
+}
+
+/// プログラム番号をキーにした音量の表。既定値以外のものだけを保存する
+fn velocity_per_program(table: &Table) -> [u8; 128] {
+    let mut velocity_per_program = [DEFAULT_VELOCITY; 128];
+    let Some(values) = table
+        .get("velocity_per_program")
+        .and_then(|x| x.as_table_like())
+    else {
+        return velocity_per_program;
+    };
+    for (program_no, vel) in values.iter() {
+        let (Ok(program_no), Some(vel)) = (program_no.parse::<usize>(), vel.as_integer()) else {
+            eprintln!("invalid velocity_per_program: {} = {}", program_no, vel);
+            continue;
+        };
+        if let Some(x) = velocity_per_program.get_mut(program_no) {
+            *x = vel.clamp(1, 127) as u8;
+        }
+    }
+    velocity_per_program
+}
+
+fn control_scheme(table: &Table) -> Option<ControlScheme> {
+    let value = string(table, "control_scheme")?;
+    let control_scheme = ControlScheme::parse(value);
+    if control_scheme.is_none() {
+        eprintln!("unknown control_scheme: {}", value);
+    }
+    control_scheme
+}
+
 #[derive(Clone, CopyGetters, Getters, MutGetters, Setters)]
 pub struct KeyboardSettings {
     #[getset(get_copy = "pub", set = "pub")]
     octave: u8,
+    /// 半音単位の移調
+    #[getset(get_copy = "pub", set = "pub")]
+    transpose: i8,
     #[getset(get_copy = "pub", set = "pub")]
     program_no: u8,
+    /// 鍵盤を左右に分ける位置。これより左の鍵盤は split_program_no の音色を別のチャンネルで鳴らす
+    #[getset(get_copy = "pub", set = "pub")]
+    split_point: Option<u8>,
+    #[getset(get_copy = "pub", set = "pub")]
+    split_program_no: u8,
+    /// 分けた左側のオクターブ
+    #[getset(get_copy = "pub", set = "pub")]
+    split_octave: u8,
     #[getset(get = "pub", get_mut = "pub")]
     velocity_per_program: [u8; 128],
     #[getset(get_copy = "pub", set = "pub")]
     reverb: bool,
     #[getset(get_copy = "pub", set = "pub")]
     chorus: bool,
+    /// 半音の 1/12 単位のずれ (-12..=11)
+    #[getset(get_copy = "pub", set = "pub")]
+    tuning: i32,
+    /// セント単位の微調整
+    #[getset(get_copy = "pub", set = "pub")]
+    fine_tune: i32,
+    /// temperaments の番号
+    #[getset(get_copy = "pub", set = "pub")]
+    temperament: u8,
+    /// 音律の主音の音名 (0 が C)
+    #[getset(get_copy = "pub", set = "pub")]
+    temperament_root: u8,
+    /// 未指定なら全体の設定に従う
+    #[getset(get_copy = "pub", set = "pub")]
+    control_scheme: Option<ControlScheme>,
+    /// soundfonts の番号。未指定ならバンクとプログラム番号だけで音色を選ぶ
+    #[getset(get_copy = "pub", set = "pub")]
+    soundfont: Option<u8>,
+    #[getset(get_copy = "pub", set = "pub")]
+    bank: u16,
+    /// 外部の音源への MIDI 出力。raw MIDI デバイスのパスか seq:<クライアント>:<ポート>
+    #[getset(get = "pub", set = "pub")]
+    midi_output: Option<String>,
+    /// false なら内蔵の音源を鳴らさず MIDI 出力だけに送る
+    #[getset(get_copy = "pub", set = "pub")]
+    internal_synth: bool,
 }
 
 impl Default for KeyboardSettings {
     fn default() -> Self {
         Self {
             octave: 5,
+            transpose: 0,
             program_no: 0,
-            velocity_per_program: [100; 128],
+            split_point: None,
+            split_program_no: 0,
+            split_octave: 4,
+            velocity_per_program: [DEFAULT_VELOCITY; 128],
             reverb: false,
             chorus: false,
+            tuning: 0,
+            fine_tune: 0,
+            temperament: 0,
+            temperament_root: 0,
+            control_scheme: None,
+            soundfont: None,
+            bank: 0,
+            midi_output: None,
+            internal_synth: true,
+        }
+    }
+}
+
+#[derive(Clone, CopyGetters, Getters)]
+pub struct SoundfontSettings {
+    #[get = "pub"]
+    path: String,
+    #[get_copy = "pub"]
+    bank_offset: i32,
+}
+
+impl SoundfontSettings {
+    fn load(doc: &Document) -> Vec<Self> {
+        let Some(soundfonts) = doc.get("soundfonts").and_then(|x| x.as_array_of_tables()) else {
+            return vec![Self {
+                path: DEFAULT_SOUNDFONT.to_owned(),
+                bank_offset: 0,
+            }];
+        };
+        soundfonts
+            .iter()
+            .filter_map(|item| {
+                let Some(path) = string(item, "path") else {
+                    eprintln!("soundfont without path: {}", item);
+                    return None;
+                };
+                Some(Self {
+                    path: path.to_owned(),
+                    bank_offset: integer(item, "bank_offset").unwrap_or(0) as i32,
+                })
+            })
+            .collect()
+    }
+}
+
+/// 相対パスを設定ファイルのディレクトリから解決する
+fn resolve(path: &str) -> String {
+    settings_path()
+        .parent()
+        .unwrap()
+        .join(path)
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn backing_track(doc: &Document) -> String {
+    resolve(string(doc.as_table(), "backing_track").unwrap_or(DEFAULT_BACKING_TRACK))
+}
+
+/// 音律。scl が無ければ組み込みの音律
+#[derive(Clone, Getters)]
+pub struct TemperamentSettings {
+    #[get = "pub"]
+    name: String,
+    /// 相対パスは設定ファイルのディレクトリから
+    #[get = "pub"]
+    scl: Option<String>,
+    #[get = "pub"]
+    kbm: Option<String>,
+}
+
+impl TemperamentSettings {
+    /// 組み込みの音律のあとに [[temperaments]] を並べる
+    fn load(doc: &Document) -> Vec<Self> {
+        let presets = PRESETS.iter().map(|name| Self {
+            name: (*name).to_owned(),
+            scl: None,
+            kbm: None,
+        });
+        let files = doc
+            .get("temperaments")
+            .and_then(|x| x.as_array_of_tables())
+            .into_iter()
+            .flat_map(|x| x.iter())
+            .filter_map(|item| {
+                let (Some(name), Some(scl)) = (string(item, "name"), string(item, "scl")) else {
+                    eprintln!("temperament without name or scl: {}", item);
+                    return None;
+                };
+                Some(Self {
+                    name: name.to_owned(),
+                    scl: Some(resolve(scl)),
+                    kbm: string(item, "kbm").map(resolve),
+                })
+            });
+        presets.chain(files).collect()
+    }
+}
+
+/// 左右どちらにも鍵盤が残る位置 (1..=23) だけを受け付ける
+fn split_point(table: &Table) -> Option<u8> {
+    let item = table.get("split_point")?;
+    let split_point = integer(table, "split_point").filter(|x| (1..24).contains(x));
+    if split_point.is_none() {
+        eprintln!("invalid split_point: {} (expected 1..=23)", item);
+    }
+    split_point.map(|x| x as u8)
+}
+
+/// キーボードの左側に使うチャンネル。分けているかどうかによらずキーボードごとに決まっている
+///
+/// 10..=15 を使うので、キーボードのチャンネル (0..=8) とも打楽器のチャンネル (9) とも重ならない
+pub fn split_channel_of(idx: u8) -> Option<u8> {
+    (idx < SPLIT_KEYBOARDS).then(|| 15 - idx)
+}
+
+fn temperament(table: &Table, temperaments: &[TemperamentSettings]) -> u8 {
+    let Some(name) = string(table, "temperament") else {
+        return 0;
+    };
+    let idx = temperaments.iter().position(|x| x.name == name);
+    if idx.is_none() {
+        eprintln!("unknown temperament: {}", name);
+    }
+    idx.unwrap_or(0) as u8
+}
+
+fn a4_hz(doc: &Document) -> u16 {
+    let Some(item) = doc.get("a4_hz") else {
+        return DEFAULT_A4_HZ;
+    };
+    match integer(doc.as_table(), "a4_hz").and_then(|x| u16::try_from(x).ok()) {
+        Some(value) if A4_HZ_RANGE.contains(&value) => value,
+        _ => {
+            eprintln!(
+                "invalid a4_hz: {} (expected {}..={})",
+                item,
+                A4_HZ_RANGE.start(),
+                A4_HZ_RANGE.end()
+            );
+            DEFAULT_A4_HZ
+        }
+    }
+}
+
+/// 補間方式の名前と FluidSynth の値
+const INTERPOLATIONS: [(&str, i32); 4] = [
+    ("none", 0),
+    ("linear", 1),
+    ("4th_order", 4),
+    ("7th_order", 7),
+];
+
+/// 範囲外や型の違う値は警告してデフォルト値にする
+fn checked_value<T: PartialOrd + Display>(
+    section: &str,
+    table: &Table,
+    key: &str,
+    get: fn(&Table, &str) -> Option<T>,
+    range: RangeInclusive<T>,
+    default: T,
+) -> T {
+    let Some(item) = table.get(key) else {
+        return default;
+    };
+    match get(table, key) {
+        Some(value) if range.contains(&value) => value,
+        _ => {
+            eprintln!(
+                "invalid {}.{}: {} (expected {}..={})",
+                section,
+                key,
+                item,
+                range.start(),
+                range.end()
+            );
+            default
+        }
+    }
+}
+
+/// FluidSynth のオーディオドライバーと合成の設定
+///
+/// Pi の機種や USB DAC によって適切なバッファーサイズが異なるため設定ファイルで変えられるようにする
+#[cfg_attr(not(feature = "fluidsynth"), allow(dead_code))]
+#[derive(Clone, CopyGetters, Debug, Getters)]
+pub struct AudioSettings {
+    #[get = "pub"]
+    driver: String,
+    /// 未指定ならドライバーのデフォルト
+    #[get = "pub"]
+    device: Option<String>,
+    #[get_copy = "pub"]
+    sample_rate: f64,
+    #[get_copy = "pub"]
+    periods: i32,
+    #[get_copy = "pub"]
+    period_size: i32,
+    #[get_copy = "pub"]
+    polyphony: i32,
+    #[get_copy = "pub"]
+    gain: f64,
+    #[get_copy = "pub"]
+    interpolation: i32,
+}
+
+impl Default for AudioSettings {
+    fn default() -> Self {
+        Self {
+            driver: "alsa".to_owned(),
+            device: None,
+            sample_rate: 44100.0,
+            periods: 4,
+            period_size: 444,
+            polyphony: 256,
+            gain: 0.2,
+            interpolation: 4,
+        }
+    }
+}
+
+impl AudioSettings {
+    fn load(doc: &Document) -> Self {
+        let default = Self::default();
+        let Some(table) = doc.get("audio").and_then(|x| x.as_table()) else {
+            return default;
+        };
+        let driver = match table.get("driver") {
+            None => default.driver,
+            Some(item) => match item.as_str() {
+                Some(driver) if !driver.is_empty() => driver.to_owned(),
+                _ => {
+                    eprintln!("invalid audio.driver: {}", item);
+                    default.driver
+                }
+            },
+        };
+        let interpolation = match table.get("interpolation") {
+            None => default.interpolation,
+            Some(item) => INTERPOLATIONS
+                .iter()
+                .find(|(name, _)| item.as_str() == Some(name))
+                .map(|&(_, value)| value)
+                .unwrap_or_else(|| {
+                    eprintln!("invalid audio.interpolation: {}", item);
+                    default.interpolation
+                }),
+        };
+        let int32 = |table: &Table, key: &str| integer(table, key).map(|x| x as i32);
+        Self {
+            driver,
+            device: string(table, "device").map(|x| x.to_owned()),
+            sample_rate: checked_value(
+                "audio",
+                table,
+                "sample_rate",
+                number,
+                8000.0..=96000.0,
+                default.sample_rate,
+            ),
+            periods: checked_value("audio", table, "periods", int32, 2..=64, default.periods),
+            period_size: checked_value(
+                "audio",
+                table,
+                "period_size",
+                int32,
+                64..=8192,
+                default.period_size,
+            ),
+            polyphony: checked_value(
+                "audio",
+                table,
+                "polyphony",
+                int32,
+                1..=65535,
+                default.polyphony,
+            ),
+            gain: checked_value("audio", table, "gain", number, 0.0..=10.0, default.gain),
+            interpolation,
+        }
+    }
+}
+
+/// 演奏の録音と SMF の書き出しの設定
+#[derive(Clone, CopyGetters, Debug, Getters)]
+pub struct RecordingSettings {
+    /// 録音と SMF を書き出すディレクトリ。書き込める /boot に置く
+    #[get = "pub"]
+    dir: String,
+    /// 常に保持しておく直近の演奏の長さ (分)
+    #[get_copy = "pub"]
+    rolling_buffer_minutes: i32,
+}
+
+impl Default for RecordingSettings {
+    fn default() -> Self {
+        Self {
+            dir: "/boot/recordings".to_owned(),
+            rolling_buffer_minutes: 5,
+        }
+    }
+}
+
+impl RecordingSettings {
+    fn load(doc: &Document) -> Self {
+        let default = Self::default();
+        let Some(table) = doc.get("recording").and_then(|x| x.as_table()) else {
+            return default;
+        };
+        Self {
+            dir: resolve(string(table, "dir").unwrap_or(&default.dir)),
+            rolling_buffer_minutes: checked_value(
+                "recording",
+                table,
+                "rolling_buffer_minutes",
+                |table, key| integer(table, key).map(|x| x as i32),
+                1..=60,
+                default.rolling_buffer_minutes,
+            ),
         }
     }
 }
@@ -67,7 +551,24 @@ impl Default for KeyboardSettings {
 pub struct SynthesizerSettings {
     #[get = "pub"]
     keyboards: Vec<KeyboardSettings>,
+    #[getset(get_copy = "pub", set = "pub")]
+    default_control_scheme: ControlScheme,
+    #[get = "pub"]
+    soundfonts: Vec<SoundfontSettings>,
+    #[get = "pub"]
+    temperaments: Vec<TemperamentSettings>,
+    #[get = "pub"]
+    audio: AudioSettings,
+    #[get = "pub"]
+    recording: RecordingSettings,
+    /// 基準ピッチ (A4 の周波数)
+    #[getset(get_copy = "pub", set = "pub")]
+    a4_hz: u16,
+    /// 伴奏の SMF。相対パスは設定ファイルのディレクトリから
+    #[get = "pub"]
+    backing_track: String,
     last_modify_timestamp: Arc<AtomicU64>,
+    persistent: bool,
 }
 
 impl SynthesizerSettings {
@@ -89,8 +590,48 @@ impl SynthesizerSettings {
         &mut self.keyboards[idx]
     }
 
+    pub fn control_scheme(&self, idx: u8) -> ControlScheme {
+        self.keyboards
+            .get(idx as usize)
+            .and_then(|keyboard| keyboard.control_scheme)
+            .unwrap_or(self.default_control_scheme)
+    }
+
+    /// 鍵盤を分けていれば、その左側のチャンネル
+    pub fn split_channel(&self, idx: u8) -> Option<u8> {
+        self.keyboards.get(idx as usize)?.split_point?;
+        split_channel_of(idx)
+    }
+
+    /// 設定ファイルを読み書きしない設定。テストやリプレイに使う
+    pub fn ephemeral() -> Self {
+        Self {
+            keyboards: Vec::new(),
+            default_control_scheme: ControlScheme::default(),
+            soundfonts: Vec::new(),
+            temperaments: TemperamentSettings::load(&Document::new()),
+            audio: AudioSettings::default(),
+            recording: RecordingSettings::default(),
+            a4_hz: DEFAULT_A4_HZ,
+            backing_track: backing_track(&Document::new()),
+            last_modify_timestamp: Arc::default(),
+            persistent: false,
+        }
+    }
+
+    /// 読み込んだ設定を変更しても保存しないようにする
+    pub fn into_ephemeral(self) -> Self {
+        Self {
+            persistent: false,
+            ..self
+        }
+    }
+
     pub fn load() -> Self {
         let doc = read();
+        let temperaments = TemperamentSettings::load(&doc);
+        let transpose_limit = TRANSPOSE_LIMIT as i64;
+        let fine_tune_limit = FINE_TUNE_LIMIT as i64;
         Self {
             keyboards: doc
                 .get("keyboards")
@@ -98,17 +639,41 @@ impl SynthesizerSettings {
                 .iter()
                 .flat_map(|x| x.iter())
                 .map(|item| KeyboardSettings {
-                    octave: integer(item, "octave").unwrap_or(5) as u8,
+                    octave: clamped(item, "octave", 0..=MAX_OCTAVE as i64, 5) as u8,
+                    transpose: clamped(item, "transpose", -transpose_limit..=transpose_limit, 0)
+                        as i8,
                     program_no: integer(item, "program_no").unwrap_or(0) as u8,
-                    velocity_per_program: [100; 128],
+                    split_point: split_point(item),
+                    split_program_no: integer(item, "split_program_no").unwrap_or(0) as u8,
+                    split_octave: clamped(item, "split_octave", 0..=MAX_OCTAVE as i64, 4) as u8,
+                    velocity_per_program: velocity_per_program(item),
                     reverb: bool(item, "reverb").unwrap_or(false),
                     chorus: bool(item, "chorus").unwrap_or(false),
+                    tuning: clamped(item, "tuning", -12..=11, 0) as i32,
+                    fine_tune: clamped(item, "fine_tune", -fine_tune_limit..=fine_tune_limit, 0)
+                        as i32,
+                    temperament: temperament(item, &temperaments),
+                    temperament_root: integer(item, "temperament_root").unwrap_or(0) as u8 % 12,
+                    control_scheme: control_scheme(item),
+                    soundfont: integer(item, "soundfont").map(|x| x as u8),
+                    bank: integer(item, "bank").unwrap_or(0) as u16,
+                    midi_output: string(item, "midi_output").map(|x| x.to_owned()),
+                    internal_synth: bool(item, "internal_synth").unwrap_or(true),
                 })
                 .collect(),
+            default_control_scheme: control_scheme(doc.as_table()).unwrap_or_default(),
+            soundfonts: SoundfontSettings::load(&doc),
+            temperaments,
+            audio: AudioSettings::load(&doc),
+            recording: RecordingSettings::load(&doc),
+            a4_hz: a4_hz(&doc),
+            backing_track: backing_track(&doc),
             last_modify_timestamp: Arc::default(),
+            persistent: true,
         }
     }
 
+    /// WRITE_LOCK を取った状態で呼ぶ
     fn save(&self) {
         let mut doc = read();
         let keyboards = doc
@@ -123,18 +688,46 @@ impl SynthesizerSettings {
         for (idx, keyboard) in self.keyboards.iter().enumerate() {
             let table = keyboards.get_mut(idx).unwrap();
             put(table, "octave", keyboard.octave as i64);
+            put(table, "transpose", keyboard.transpose as i64);
             put(table, "program_no", keyboard.program_no as i64);
+            if let Some(split_point) = keyboard.split_point {
+                put(table, "split_point", split_point as i64);
+            } else {
+                table.remove("split_point");
+            }
+            put(table, "split_program_no", keyboard.split_program_no as i64);
+            put(table, "split_octave", keyboard.split_octave as i64);
             put(table, "reverb", keyboard.reverb);
             put(table, "chorus", keyboard.chorus);
+            put(table, "tuning", keyboard.tuning as i64);
+            put(table, "fine_tune", keyboard.fine_tune as i64);
+            if let Some(temperament) = self.temperaments.get(keyboard.temperament as usize) {
+                put(table, "temperament", temperament.name.as_str());
+            }
+            put(table, "temperament_root", keyboard.temperament_root as i64);
+            let velocity_per_program: InlineTable = keyboard
+                .velocity_per_program
+                .iter()
+                .enumerate()
+                .filter(|(_, &vel)| vel != DEFAULT_VELOCITY)
+                .map(|(program_no, &vel)| (program_no.to_string(), vel as i64))
+                .collect();
+            if velocity_per_program.is_empty() {
+                table.remove("velocity_per_program");
+            } else {
+                put(table, "velocity_per_program", velocity_per_program);
+            }
             table.sort_values();
         }
+        put(doc.as_table_mut(), "a4_hz", self.a4_hz as i64);
         doc.sort_values();
-        if let Err(err) = fs::write(PATH, doc.to_string()) {
-            eprintln!("{}", err);
-        }
+        write(&doc);
     }
 
     pub fn queue_save(&mut self) {
+        if !self.persistent {
+            return;
+        }
         let last_modify_timestamp = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
@@ -144,10 +737,314 @@ impl SynthesizerSettings {
         let store = self.clone();
         spawn(move || {
             sleep(Duration::from_secs(1));
-            if store.last_modify_timestamp.load(Ordering::Relaxed) == last_modify_timestamp {
+            let _lock = WRITE_LOCK.lock().unwrap();
+            if store
+                .last_modify_timestamp
+                .compare_exchange(
+                    last_modify_timestamp,
+                    0,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+            {
                 store.save();
                 println!("saved({last_modify_timestamp})");
             }
         });
     }
+
+    /// 保存待ちの変更があればすぐに保存する。書き込み中の保存があれば終わるまで待つ
+    pub fn flush_save(&self) {
+        let _lock = WRITE_LOCK.lock().unwrap();
+        let last_modify_timestamp = self.last_modify_timestamp.swap(0, Ordering::Relaxed);
+        if last_modify_timestamp != 0 {
+            self.save();
+            println!("saved({last_modify_timestamp})");
+        }
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// 物理デバイスとキーボード番号の対応表
+///
+/// USB のシリアル番号 (無ければ物理パス) をキーにキーボード番号を固定し、
+/// 抜き差しでチャンネルや設定が入れ替わらないようにする。
+/// 番号を使い切ったら、接続していないデバイスのうち最も長く使われていないものの番号を譲る
+#[derive(Clone, Default)]
+pub struct DeviceRegistry {
+    /// (キーボード番号, 最後に接続した UNIX 時刻)
+    slots: BTreeMap<String, (usize, u64)>,
+    /// 接続中のデバイス。これらの番号は譲らない
+    connected: BTreeSet<String>,
+    /// 空きが無くて割り当てられなかったデバイス。報告を 1 度にする
+    rejected: BTreeSet<String>,
+}
+
+impl DeviceRegistry {
+    pub fn load() -> Self {
+        let doc = read();
+        Self {
+            slots: doc
+                .get("devices")
+                .and_then(|x| x.as_array_of_tables())
+                .iter()
+                .flat_map(|x| x.iter())
+                .filter_map(|item| {
+                    let id = string(item, "id")?;
+                    let keyboard = integer(item, "keyboard")?;
+                    if !(0..KEYBOARD_SLOTS as i64).contains(&keyboard) {
+                        eprintln!("invalid keyboard for device {}: {}", id, keyboard);
+                        return None;
+                    }
+                    let last_seen = integer(item, "last_seen").unwrap_or(0) as u64;
+                    Some((id.to_owned(), (keyboard as usize, last_seen)))
+                })
+                .collect(),
+            connected: BTreeSet::new(),
+            rejected: BTreeSet::new(),
+        }
+    }
+
+    fn save(&self) {
+        let _lock = WRITE_LOCK.lock().unwrap();
+        let mut doc = read();
+        let mut slots: Vec<_> = self.slots.iter().collect();
+        slots.sort_by_key(|(_, &(slot, _))| slot);
+        let mut devices = ArrayOfTables::new();
+        for (id, &(slot, last_seen)) in slots {
+            let mut table = Table::new();
+            put(&mut table, "id", id.as_str());
+            put(&mut table, "keyboard", slot as i64);
+            put(&mut table, "last_seen", last_seen as i64);
+            devices.push(table);
+        }
+        put_item(doc.as_table_mut(), "devices", Item::ArrayOfTables(devices));
+        write(&doc);
+    }
+
+    /// 接続したデバイスのキーボード番号を返す
+    ///
+    /// 未登録なら空いている最小の番号を、空きが無ければ接続していないデバイスから
+    /// 最も長く使われていないものの番号を割り当てる。全ての番号が接続中なら None
+    pub fn slot(&mut self, id: &str) -> Option<usize> {
+        let slot = match self.slots.get(id) {
+            Some(&(slot, _)) => slot,
+            None => self.free_slot(id)?,
+        };
+        self.slots.insert(id.to_owned(), (slot, unix_time()));
+        self.connected.insert(id.to_owned());
+        self.rejected.remove(id);
+        self.save();
+        Some(slot)
+    }
+
+    fn free_slot(&mut self, id: &str) -> Option<usize> {
+        if let Some(slot) =
+            (0..KEYBOARD_SLOTS).find(|slot| !self.slots.values().any(|&(x, _)| x == *slot))
+        {
+            return Some(slot);
+        }
+        let Some(old) = self
+            .slots
+            .iter()
+            .filter(|(id, _)| !self.connected.contains(*id))
+            .min_by_key(|(_, &(_, last_seen))| last_seen)
+            .map(|(id, _)| id.clone())
+        else {
+            if self.rejected.insert(id.to_owned()) {
+                eprintln!("no keyboard slot left for {}", id);
+            }
+            return None;
+        };
+        let (slot, _) = self.slots.remove(&old).unwrap();
+        println!("keyboard {} moved from {} to {}", slot, old, id);
+        Some(slot)
+    }
+
+    /// 切断したデバイスの番号を、空きが無いときに譲れるようにする
+    pub fn release(&mut self, slot: usize) {
+        let slots = &self.slots;
+        self.connected
+            .retain(|id| slots.get(id).is_none_or(|&(x, _)| x != slot));
+    }
+}
+
+/// 入力デバイスの種類ごとの設定
+///
+/// name / vendor / product / key_count のうち指定されたものが全て一致したデバイスに適用する
+#[derive(Clone, CopyGetters, Getters)]
+pub struct DeviceProfile {
+    #[get = "pub"]
+    name: Option<String>,
+    #[get_copy = "pub"]
+    vendor: Option<u16>,
+    #[get_copy = "pub"]
+    product: Option<u16>,
+    #[get_copy = "pub"]
+    key_count: Option<usize>,
+    #[get = "pub"]
+    key_map: KeyMap,
+}
+
+impl DeviceProfile {
+    pub fn konami() -> Self {
+        Self {
+            name: Some("KONAMI USB Multipurpose Controller".to_owned()),
+            vendor: None,
+            product: None,
+            key_count: Some(34),
+            key_map: KeyMap::konami(),
+        }
+    }
+
+    fn parse_key_map(table: &Table) -> KeyMap {
+        KeyMap::new(
+            table
+                .iter()
+                .filter_map(|(code, item)| {
+                    let Ok(code) = code.parse() else {
+                        eprintln!("invalid key code: {code}");
+                        return None;
+                    };
+                    let input = if let Some(no) = item.as_integer() {
+                        Input::key(no)
+                    } else if let Some(name) = item.as_str() {
+                        if name == "none" {
+                            return Some((code, None));
+                        }
+                        Input::from_name(name)
+                    } else {
+                        None
+                    };
+                    if input.is_none() {
+                        eprintln!("invalid input for key code {code}: {item}");
+                    }
+                    input.map(|input| (code, Some(input)))
+                })
+                .collect(),
+        )
+    }
+
+    /// 設定ファイルのプロファイルに組み込みの KONAMI 用プロファイルを加えて返す
+    pub fn load() -> Vec<Self> {
+        let doc = read();
+        doc.get("profiles")
+            .and_then(|x| x.as_array_of_tables())
+            .iter()
+            .flat_map(|x| x.iter())
+            .filter_map(|item| {
+                let profile = Self {
+                    name: string(item, "name").map(|x| x.to_owned()),
+                    vendor: integer(item, "vendor").map(|x| x as u16),
+                    product: integer(item, "product").map(|x| x as u16),
+                    key_count: integer(item, "key_count").map(|x| x as usize),
+                    key_map: item
+                        .get("codes")
+                        .and_then(|x| x.as_table())
+                        .map(Self::parse_key_map)
+                        .unwrap_or_else(KeyMap::konami),
+                };
+                // 何も指定しないとキーボードやマウスまで含めて全てのデバイスに一致してしまう
+                if profile.name.is_none()
+                    && profile.vendor.is_none()
+                    && profile.product.is_none()
+                    && profile.key_count.is_none()
+                {
+                    eprintln!(
+                        "profile without name, vendor, product or key_count: {}",
+                        item
+                    );
+                    return None;
+                }
+                Some(profile)
+            })
+            .chain([Self::konami()])
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyboard_settings_round_trip() {
+        let _ = fs::remove_file(settings_path());
+        let mut settings = SynthesizerSettings::load();
+        let keyboard = settings.get_or_create_keyboard_mut(1);
+        keyboard.set_octave(3);
+        keyboard.set_transpose(-5);
+        keyboard.set_program_no(24);
+        keyboard.set_split_point(Some(12));
+        keyboard.set_split_program_no(32);
+        keyboard.set_split_octave(2);
+        keyboard.set_tuning(-7);
+        keyboard.set_fine_tune(30);
+        keyboard.velocity_per_program_mut()[24] = 64;
+        settings.save();
+
+        let settings = SynthesizerSettings::load();
+        let _ = fs::remove_file(settings_path());
+        assert_eq!(settings.keyboards().len(), 2);
+        assert_eq!(settings.keyboards()[0].split_point(), None);
+        let keyboard = &settings.keyboards()[1];
+        assert_eq!(keyboard.octave(), 3);
+        assert_eq!(keyboard.transpose(), -5);
+        assert_eq!(keyboard.program_no(), 24);
+        assert_eq!(keyboard.split_point(), Some(12));
+        assert_eq!(keyboard.split_program_no(), 32);
+        assert_eq!(keyboard.split_octave(), 2);
+        assert_eq!(keyboard.tuning(), -7);
+        assert_eq!(keyboard.fine_tune(), 30);
+        assert_eq!(keyboard.velocity_per_program()[24], 64);
+        assert_eq!(keyboard.velocity_per_program()[0], DEFAULT_VELOCITY);
+        assert_eq!(settings.split_channel(1), Some(14));
+    }
+
+    #[test]
+    fn out_of_range_keyboard_values_are_clamped() {
+        fs::write(
+            settings_path(),
+            "[[keyboards]]\noctave = 20\ntranspose = -200\nsplit_octave = -1\n\
+             tuning = 30\nfine_tune = 300\n",
+        )
+        .unwrap();
+        let settings = SynthesizerSettings::load();
+        let _ = fs::remove_file(settings_path());
+        let keyboard = &settings.keyboards()[0];
+        assert_eq!(keyboard.octave(), MAX_OCTAVE);
+        assert_eq!(keyboard.transpose(), -TRANSPOSE_LIMIT);
+        assert_eq!(keyboard.split_octave(), 0);
+        assert_eq!(keyboard.tuning(), 11);
+        assert_eq!(keyboard.fine_tune(), FINE_TUNE_LIMIT);
+    }
+
+    #[test]
+    fn device_slot_goes_to_least_recently_seen_disconnected_device() {
+        let _ = fs::remove_file(settings_path());
+        let mut registry = DeviceRegistry::load();
+        for idx in 0..KEYBOARD_SLOTS {
+            assert_eq!(registry.slot(&format!("dev{}", idx)), Some(idx));
+        }
+        assert_eq!(registry.slot("new"), None);
+
+        registry.release(2);
+        registry.release(5);
+        registry.slots.get_mut("dev5").unwrap().1 = 1;
+        assert_eq!(registry.slot("new"), Some(5));
+        assert_eq!(registry.slot("dev2"), Some(2));
+        assert_eq!(registry.slot("dev5"), None);
+
+        let registry = DeviceRegistry::load();
+        let _ = fs::remove_file(settings_path());
+        assert_eq!(registry.slots.get("new").map(|x| x.0), Some(5));
+        assert!(!registry.slots.contains_key("dev5"));
+    }
 }
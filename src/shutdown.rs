@@ -0,0 +1,45 @@
+use std::{
+    io,
+    sync::{mpsc, Arc, Mutex},
+    thread::spawn,
+};
+
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    iterator::Signals,
+};
+
+use crate::kmctrler::Event;
+
+/// SIGTERM / SIGINT を受けるまで入力を流す
+///
+/// シグナルを受けるか入力が終わると送信側を閉じるので、受信側は残りを処理したあと終了できる
+pub fn until_signal(
+    rx: mpsc::Receiver<(usize, Event)>,
+) -> io::Result<mpsc::Receiver<(usize, Event)>> {
+    let mut signals = Signals::new([SIGTERM, SIGINT])?;
+    let (tx, new_rx) = mpsc::channel();
+    let tx = Arc::new(Mutex::new(Some(tx)));
+    {
+        let tx = tx.clone();
+        spawn(move || {
+            if let Some(signal) = signals.forever().next() {
+                println!("signal {}", signal);
+                tx.lock().unwrap().take();
+            }
+        });
+    }
+    spawn(move || {
+        for input in rx {
+            let tx = tx.lock().unwrap();
+            let Some(tx) = tx.as_ref() else {
+                return;
+            };
+            if tx.send(input).is_err() {
+                return;
+            }
+        }
+        tx.lock().unwrap().take();
+    });
+    Ok(new_rx)
+}
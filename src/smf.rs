@@ -0,0 +1,345 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::{create_dir_all, OpenOptions},
+    io::{self, ErrorKind, Write},
+    mem::replace,
+    path::Path,
+    thread::{spawn, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    synth_backend::{
+        switch, CC_ALL_NOTES_OFF, CC_BANK_SELECT_LSB, CC_BANK_SELECT_MSB, CC_CHORUS, CC_HOLD,
+        CC_MODULATION, CC_REVERB,
+    },
+    synthctrler::Event,
+};
+
+/// 4 分音符あたりのティック数。テンポ 120 なので 1 ティックが 1 ミリ秒になる
+const DIVISION: u16 = 500;
+const TEMPO: u32 = 500_000;
+
+fn cc(chan: u8, ctrl: u8, value: u8) -> Vec<u8> {
+    vec![0xB0 | chan, ctrl, value]
+}
+
+/// synthctrler::Event を (チャンネル, MIDI メッセージ) にする。MIDI で表せないものは空
+fn messages(ev: Event) -> Vec<(u8, Vec<u8>)> {
+    let (chan, messages) = match ev {
+        Event::Noteon(chan, key, vel) => (chan, vec![vec![0x90 | chan, key, vel]]),
+        Event::Noteoff(chan, key) => (chan, vec![vec![0x80 | chan, key, 0]]),
+        Event::AllNotesOff(chan) => (chan, vec![cc(chan, CC_ALL_NOTES_OFF, 0)]),
+        Event::ProgramChange(chan, program) => (chan, vec![vec![0xC0 | chan, program]]),
+        Event::BankSelect(chan, _, bank) => (
+            chan,
+            vec![
+                cc(chan, CC_BANK_SELECT_MSB, (bank >> 7) as u8 & 0x7F),
+                cc(chan, CC_BANK_SELECT_LSB, bank as u8 & 0x7F),
+            ],
+        ),
+        Event::HoldOn(chan) => (chan, vec![cc(chan, CC_HOLD, switch(true))]),
+        Event::HoldOff(chan) => (chan, vec![cc(chan, CC_HOLD, switch(false))]),
+        Event::ModulationOn(chan) => (chan, vec![cc(chan, CC_MODULATION, switch(true))]),
+        Event::ModulationOff(chan) => (chan, vec![cc(chan, CC_MODULATION, switch(false))]),
+        Event::ReverbOn(chan) => (chan, vec![cc(chan, CC_REVERB, switch(true))]),
+        Event::ReverbOff(chan) => (chan, vec![cc(chan, CC_REVERB, switch(false))]),
+        Event::ChorusOn(chan) => (chan, vec![cc(chan, CC_CHORUS, switch(true))]),
+        Event::ChorusOff(chan) => (chan, vec![cc(chan, CC_CHORUS, switch(false))]),
+        Event::Tuning(..)
+        | Event::Temperament(..)
+        | Event::ConcertPitch(_)
+        | Event::RecordingOn
+        | Event::RecordingOff
+        | Event::SplitMidiFile
+        | Event::DumpRollingBuffer
+        | Event::BackingTrack(_) => return Vec::new(),
+    };
+    messages.into_iter().map(|x| (chan, x)).collect()
+}
+
+fn variable_length(mut value: u32, buf: &mut Vec<u8>) {
+    let mut bytes = vec![(value & 0x7F) as u8];
+    value >>= 7;
+    while value > 0 {
+        bytes.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.extend(bytes.iter().rev());
+}
+
+fn track(events: &[(u64, Vec<u8>)]) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut last = 0;
+    for (timestamp, message) in events {
+        variable_length((timestamp - last) as u32, &mut data);
+        data.extend(message);
+        last = *timestamp;
+    }
+    data.extend([0x00, 0xFF, 0x2F, 0x00]);
+    let mut chunk = b"MTrk".to_vec();
+    chunk.extend((data.len() as u32).to_be_bytes());
+    chunk.extend(data);
+    chunk
+}
+
+/// 経過ミリ秒付きのイベントを、チャンネルごとにトラックを分けた SMF (フォーマット 1) にする
+///
+/// 先頭のトラックはテンポのみ
+pub fn encode(events: &[(u64, Event)]) -> Vec<u8> {
+    let mut tracks = BTreeMap::new();
+    for &(timestamp, ev) in events {
+        for (chan, message) in messages(ev) {
+            tracks
+                .entry(chan)
+                .or_insert_with(Vec::new)
+                .push((timestamp, message));
+        }
+    }
+
+    let mut smf = b"MThd".to_vec();
+    smf.extend(6u32.to_be_bytes());
+    smf.extend(1u16.to_be_bytes());
+    smf.extend((tracks.len() as u16 + 1).to_be_bytes());
+    smf.extend(DIVISION.to_be_bytes());
+    let tempo = TEMPO.to_be_bytes();
+    smf.extend(track(&[(
+        0,
+        vec![0xFF, 0x51, 0x03, tempo[1], tempo[2], tempo[3]],
+    )]));
+    for (chan, events) in tracks {
+        let name = format!("keyboard {}", chan);
+        let mut name_event = vec![0xFF, 0x03, name.len() as u8];
+        name_event.extend(name.as_bytes());
+        let events: Vec<_> = [(0, name_event)].into_iter().chain(events).collect();
+        smf.extend(track(&events));
+    }
+    smf
+}
+
+/// dir に書き出した時の UNIX 時刻を名前にした SMF を書き出す。同じ名前があれば連番を付ける
+pub fn write_file(dir: &str, events: &[(u64, Event)]) -> io::Result<()> {
+    create_dir_all(dir)?;
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let smf = encode(events);
+    for no in 0.. {
+        let name = match no {
+            0 => format!("{}.mid", timestamp),
+            _ => format!("{}-{}.mid", timestamp, no),
+        };
+        let path = Path::new(dir).join(name);
+        let mut file = match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        };
+        file.write_all(&smf)?;
+        println!("saved: {}", path.display());
+        break;
+    }
+    Ok(())
+}
+
+fn carry_slot(ev: &Event) -> Option<(u8, u8)> {
+    match ev {
+        Event::BankSelect(chan, ..) => Some((*chan, 0)),
+        Event::ProgramChange(chan, _) => Some((*chan, 1)),
+        Event::ReverbOn(chan) | Event::ReverbOff(chan) => Some((*chan, 2)),
+        Event::ChorusOn(chan) | Event::ChorusOff(chan) => Some((*chan, 3)),
+        _ => None,
+    }
+}
+
+/// チャンネルごとの最後の音色・エフェクトの設定を残す
+fn update_carry(carry: &mut Vec<Event>, ev: Event) {
+    let Some(slot) = carry_slot(&ev) else {
+        return;
+    };
+    carry.retain(|x| carry_slot(x) != Some(slot));
+    carry.push(ev);
+}
+
+/// 別スレッドで書き出す。ノートが 1 つも無ければ何もしない
+fn spawn_write(dir: &str, events: Vec<(u64, Event)>) -> Option<JoinHandle<()>> {
+    if !events.iter().any(|(_, ev)| matches!(ev, Event::Noteon(..))) {
+        return None;
+    }
+    let dir = dir.to_owned();
+    Some(spawn(move || {
+        if let Err(err) = write_file(&dir, &events) {
+            eprintln!("{}", err);
+        }
+    }))
+}
+
+/// 演奏したイベントを溜めておき、区切りごとに SMF に書き出す
+///
+/// 区切りとは別に直近の演奏を常に保持し、DumpRollingBuffer を受けるとそれを書き出す
+pub struct SmfRecorder {
+    dir: String,
+    start: Instant,
+    events: Vec<(u64, Event)>,
+    origin: Instant,
+    rolling_length: Duration,
+    /// origin からの経過ミリ秒付き
+    rolling_events: VecDeque<(u64, Event)>,
+    /// rolling_events から溢れたイベントによる音色・エフェクトの設定
+    rolling_carry: Vec<Event>,
+    /// 書き出し中のスレッド。終了時に待つ
+    writers: Vec<JoinHandle<()>>,
+}
+
+impl SmfRecorder {
+    pub fn new(dir: &str, rolling_length: Duration) -> Self {
+        Self {
+            dir: dir.to_owned(),
+            start: Instant::now(),
+            events: Vec::new(),
+            origin: Instant::now(),
+            rolling_length,
+            rolling_events: VecDeque::new(),
+            rolling_carry: Vec::new(),
+            writers: Vec::new(),
+        }
+    }
+
+    /// SplitMidiFile を受けるとそこまでを書き出して新しいファイルを始める
+    pub fn push(&mut self, ev: Event) {
+        match ev {
+            Event::SplitMidiFile => {
+                self.split();
+            }
+            Event::DumpRollingBuffer => {
+                self.dump_rolling_buffer();
+            }
+            _ => {
+                let timestamp = self.start.elapsed().as_millis() as u64;
+                self.events.push((timestamp, ev));
+                self.push_rolling(ev);
+            }
+        }
+    }
+
+    fn push_rolling(&mut self, ev: Event) {
+        let now = self.origin.elapsed().as_millis() as u64;
+        let oldest = now.saturating_sub(self.rolling_length.as_millis() as u64);
+        while let Some(&(timestamp, old)) = self.rolling_events.front() {
+            if timestamp >= oldest {
+                break;
+            }
+            update_carry(&mut self.rolling_carry, old);
+            self.rolling_events.pop_front();
+        }
+        self.rolling_events.push_back((now, ev));
+    }
+
+    /// ここまでの演奏を別スレッドで書き出す
+    ///
+    /// 次のファイルの先頭には、チャンネルごとの最後の音色・エフェクトの設定を引き継ぐ
+    fn split(&mut self) {
+        self.start = Instant::now();
+        let mut carry = Vec::new();
+        for &(_, ev) in &self.events {
+            update_carry(&mut carry, ev);
+        }
+        let events = replace(
+            &mut self.events,
+            carry.into_iter().map(|ev| (0, ev)).collect(),
+        );
+        self.spawn_write(events);
+    }
+
+    /// 直近の演奏を別スレッドで書き出す。保持している演奏はそのまま残す
+    fn dump_rolling_buffer(&mut self) {
+        let Some(&(first, _)) = self.rolling_events.front() else {
+            return;
+        };
+        let events = self
+            .rolling_carry
+            .iter()
+            .map(|&ev| (0, ev))
+            .chain(
+                self.rolling_events
+                    .iter()
+                    .map(|&(timestamp, ev)| (timestamp - first, ev)),
+            )
+            .collect();
+        self.spawn_write(events);
+    }
+
+    fn spawn_write(&mut self, events: Vec<(u64, Event)>) {
+        self.writers.retain(|writer| !writer.is_finished());
+        self.writers.extend(spawn_write(&self.dir, events));
+    }
+
+    /// 残りの演奏を書き出し、全ての書き出しが終わるまで待つ
+    pub fn finish(mut self) {
+        self.split();
+        for writer in self.writers {
+            let _ = writer.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn variable_length_quantities() {
+        for (value, expected) in [
+            (0, &[0x00][..]),
+            (0x7F, &[0x7F]),
+            (0x80, &[0x81, 0x00]),
+            (200, &[0x81, 0x48]),
+            (0x3FFF, &[0xFF, 0x7F]),
+            (0x4000, &[0x81, 0x80, 0x00]),
+            (0x0FFF_FFFF, &[0xFF, 0xFF, 0xFF, 0x7F]),
+        ] {
+            let mut buf = Vec::new();
+            variable_length(value, &mut buf);
+            assert_eq!(buf, expected, "{:#X}", value);
+        }
+    }
+
+    #[test]
+    fn encode_splits_tracks_by_channel() {
+        let smf = encode(&[
+            (0, Event::ProgramChange(0, 5)),
+            (100, Event::Noteon(0, 60, 100)),
+            (200, Event::Noteon(1, 64, 90)),
+            (300, Event::Noteoff(0, 60)),
+            (350, Event::Noteoff(1, 64)),
+            (400, Event::RecordingOn),
+        ]);
+
+        // フォーマット 1、テンポのトラックとチャンネル 0, 1 の 3 トラック、500 ティック
+        let (header, smf) = smf.split_at(14);
+        assert_eq!(
+            header,
+            [b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 1, 0, 3, 0x01, 0xF4]
+        );
+
+        let tempo = b"MTrk\0\0\0\x0B\x00\xFF\x51\x03\x07\xA1\x20\x00\xFF\x2F\x00";
+        let (track, smf) = smf.split_at(tempo.len());
+        assert_eq!(track, tempo);
+
+        let mut keyboard0 = b"MTrk\0\0\0\x1E\x00\xFF\x03\x0Akeyboard 0".to_vec();
+        keyboard0.extend([0x00, 0xC0, 5]);
+        keyboard0.extend([0x64, 0x90, 60, 100]);
+        keyboard0.extend([0x81, 0x48, 0x80, 60, 0]);
+        keyboard0.extend([0x00, 0xFF, 0x2F, 0x00]);
+        let (track, smf) = smf.split_at(keyboard0.len());
+        assert_eq!(track, keyboard0);
+
+        // 経過時間は直前のイベントからの差
+        let mut keyboard1 = b"MTrk\0\0\0\x1C\x00\xFF\x03\x0Akeyboard 1".to_vec();
+        keyboard1.extend([0x81, 0x48, 0x91, 64, 90]);
+        keyboard1.extend([0x81, 0x16, 0x81, 64, 0]);
+        keyboard1.extend([0x00, 0xFF, 0x2F, 0x00]);
+        assert_eq!(smf, keyboard1);
+    }
+}
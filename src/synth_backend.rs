@@ -0,0 +1,232 @@
+use std::sync::Mutex;
+
+use crate::synthctrler::{Event, Transport};
+
+pub const CC_BANK_SELECT_MSB: u8 = 0;
+pub const CC_MODULATION: u8 = 1;
+pub const CC_BANK_SELECT_LSB: u8 = 32;
+pub const CC_HOLD: u8 = 64;
+pub const CC_REVERB: u8 = 91;
+pub const CC_CHORUS: u8 = 93;
+pub const CC_ALL_NOTES_OFF: u8 = 123;
+
+/// 内蔵の音源のチャンネル数。後半の 16 チャンネルは伴奏が使う
+pub const SYNTH_CHANNELS: u8 = 32;
+
+pub fn switch(value: bool) -> u8 {
+    if value {
+        127
+    } else {
+        0
+    }
+}
+
+/// 調整モードの toggle が切り替え先を決めるのに使う、バックエンドの現在の状態
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SynthStatus {
+    pub recording: bool,
+    /// 伴奏を再生中か。最後まで再生し終えたら false
+    pub backing_track: bool,
+}
+
+/// synthctrler::Event の出力先
+pub trait SynthBackend {
+    fn noteon(&self, chan: u8, key: u8, vel: u8) -> bool;
+    fn noteoff(&self, chan: u8, key: u8) -> bool;
+    fn all_notes_off(&self, chan: u8) -> bool;
+    fn program_change(&self, chan: u8, program: u8) -> bool;
+    /// 以降のプログラムチェンジで使う soundfont とバンクを選ぶ
+    fn bank_select(&self, chan: u8, soundfont: Option<u8>, bank: u16) -> bool;
+    /// チャンネルごとの音程のずれを設定する
+    fn tuning(&self, chan: u8, tuning: i32, fine_tune: i32) -> bool;
+    /// チャンネルの音律を選ぶ。tuning のずれはこの上に重ねる
+    fn temperament(&self, chan: u8, temperament: u8, root: u8) -> bool;
+    fn concert_pitch(&self, a4_hz: u16) -> bool;
+    /// 合成した音声の録音を開始・停止する
+    fn recording(&self, on: bool) -> bool;
+    /// 設定ファイルで指定した SMF を伴奏として再生する
+    fn backing_track(&self, transport: Transport) -> bool;
+    fn cc(&self, chan: u8, ctrl: u8, value: u8) -> bool;
+
+    fn status(&self) -> SynthStatus {
+        SynthStatus::default()
+    }
+
+    fn process(&self, ev: Event) -> bool {
+        match ev {
+            Event::Noteon(chan, key, vel) => self.noteon(chan, key, vel),
+            Event::Noteoff(chan, key) => self.noteoff(chan, key),
+            Event::AllNotesOff(chan) => self.all_notes_off(chan),
+            Event::ProgramChange(chan, program) => self.program_change(chan, program),
+            Event::BankSelect(chan, soundfont, bank) => self.bank_select(chan, soundfont, bank),
+            Event::Tuning(chan, tuning, fine_tune) => self.tuning(chan, tuning, fine_tune),
+            Event::Temperament(chan, temperament, root) => {
+                self.temperament(chan, temperament, root)
+            }
+            Event::ConcertPitch(a4_hz) => self.concert_pitch(a4_hz),
+            Event::RecordingOn => self.recording(true),
+            Event::RecordingOff => self.recording(false),
+            Event::SplitMidiFile | Event::DumpRollingBuffer => true,
+            Event::BackingTrack(transport) => self.backing_track(transport),
+            Event::HoldOn(chan) => self.cc(chan, CC_HOLD, switch(true)),
+            Event::HoldOff(chan) => self.cc(chan, CC_HOLD, switch(false)),
+            Event::ModulationOn(chan) => self.cc(chan, CC_MODULATION, switch(true)),
+            Event::ModulationOff(chan) => self.cc(chan, CC_MODULATION, switch(false)),
+            Event::ReverbOn(chan) => self.cc(chan, CC_REVERB, switch(true)),
+            Event::ReverbOff(chan) => self.cc(chan, CC_REVERB, switch(false)),
+            Event::ChorusOn(chan) => self.cc(chan, CC_CHORUS, switch(true)),
+            Event::ChorusOff(chan) => self.cc(chan, CC_CHORUS, switch(false)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Call {
+    Noteon(u8, u8, u8),
+    Noteoff(u8, u8),
+    AllNotesOff(u8),
+    ProgramChange(u8, u8),
+    BankSelect(u8, Option<u8>, u16),
+    Tuning(u8, i32, i32),
+    Temperament(u8, u8, u8),
+    ConcertPitch(u16),
+    Recording(bool),
+    BackingTrack(Transport),
+    Cc(u8, u8, u8),
+}
+
+/// 呼び出しをメモリ上に記録するだけのバックエンド
+///
+/// libfluidsynth やオーディオデバイスの無い環境での動作確認に使う
+#[derive(Default)]
+pub struct Recorder {
+    calls: Mutex<Vec<Call>>,
+}
+
+impl Recorder {
+    pub fn calls(&self) -> Vec<Call> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    fn push(&self, call: Call) -> bool {
+        self.calls.lock().unwrap().push(call);
+        true
+    }
+}
+
+impl SynthBackend for Recorder {
+    fn noteon(&self, chan: u8, key: u8, vel: u8) -> bool {
+        self.push(Call::Noteon(chan, key, vel))
+    }
+
+    fn noteoff(&self, chan: u8, key: u8) -> bool {
+        self.push(Call::Noteoff(chan, key))
+    }
+
+    fn all_notes_off(&self, chan: u8) -> bool {
+        self.push(Call::AllNotesOff(chan))
+    }
+
+    fn program_change(&self, chan: u8, program: u8) -> bool {
+        self.push(Call::ProgramChange(chan, program))
+    }
+
+    fn bank_select(&self, chan: u8, soundfont: Option<u8>, bank: u16) -> bool {
+        self.push(Call::BankSelect(chan, soundfont, bank))
+    }
+
+    fn tuning(&self, chan: u8, tuning: i32, fine_tune: i32) -> bool {
+        self.push(Call::Tuning(chan, tuning, fine_tune))
+    }
+
+    fn temperament(&self, chan: u8, temperament: u8, root: u8) -> bool {
+        self.push(Call::Temperament(chan, temperament, root))
+    }
+
+    fn concert_pitch(&self, a4_hz: u16) -> bool {
+        self.push(Call::ConcertPitch(a4_hz))
+    }
+
+    fn recording(&self, on: bool) -> bool {
+        self.push(Call::Recording(on))
+    }
+
+    fn backing_track(&self, transport: Transport) -> bool {
+        self.push(Call::BackingTrack(transport))
+    }
+
+    fn cc(&self, chan: u8, ctrl: u8, value: u8) -> bool {
+        self.push(Call::Cc(chan, ctrl, value))
+    }
+
+    /// 記録した呼び出しのうち最後のものに従う
+    fn status(&self) -> SynthStatus {
+        let calls = self.calls.lock().unwrap();
+        SynthStatus {
+            recording: calls
+                .iter()
+                .rev()
+                .find_map(|call| match call {
+                    Call::Recording(on) => Some(*on),
+                    _ => None,
+                })
+                .unwrap_or(false),
+            backing_track: calls
+                .iter()
+                .rev()
+                .find_map(|call| match call {
+                    Call::BackingTrack(Transport::Start) => Some(true),
+                    Call::BackingTrack(Transport::Stop) => Some(false),
+                    _ => None,
+                })
+                .unwrap_or(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        kmctrler::{self, Input},
+        settings::{ControlScheme, SynthesizerSettings},
+        synthctrler::SynthCtrler,
+    };
+
+    #[test]
+    fn recorder_records_processed_events() {
+        let mut settings = SynthesizerSettings::ephemeral();
+        settings.set_default_control_scheme(ControlScheme::V2);
+        let (tx, rx) = mpsc::channel();
+        let mut ctrler = SynthCtrler::new(settings, rx);
+        for (idx, ev) in [
+            (0, kmctrler::Event::Press(Input::Key(0))),
+            (0, kmctrler::Event::Press(Input::WheelUp)),
+            (1, kmctrler::Event::NoteOn(64, 90)),
+            (0, kmctrler::Event::Release(Input::Key(0))),
+            (1, kmctrler::Event::NoteOff(64)),
+            (1, kmctrler::Event::Disconnect),
+        ] {
+            tx.send((idx, ev)).unwrap();
+        }
+        let recorder = Recorder::default();
+        while let Ok((_, output)) = ctrler.try_recv(&recorder) {
+            assert!(recorder.process(output.event));
+        }
+        assert_eq!(
+            recorder.calls(),
+            [
+                Call::Noteon(0, 60, 100),
+                Call::Cc(0, CC_MODULATION, 127),
+                Call::Noteon(1, 64, 90),
+                Call::Noteoff(0, 60),
+                Call::Noteoff(1, 64),
+                Call::Cc(1, CC_HOLD, 0),
+                Call::Cc(1, CC_MODULATION, 0),
+                Call::AllNotesOff(1),
+            ]
+        );
+    }
+}
@@ -2,12 +2,33 @@ pub mod v1;
 pub mod v2;
 pub mod v3;
 
+use std::{
+    collections::HashMap,
+    sync::mpsc::{self, RecvError, TryRecvError},
+    thread::sleep,
+    time::Duration,
+};
+
+use crate::{
+    kmctrler,
+    settings::{ControlScheme, KeyboardSettings, SynthesizerSettings},
+    synth_backend::{SynthBackend, SynthStatus},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Event {
     Noteon(u8, u8, u8),
     Noteoff(u8, u8),
     AllNotesOff(u8),
     ProgramChange(u8, u8),
-    Tuning(i32),
+    /// (チャンネル, soundfont の番号, バンク)
+    BankSelect(u8, Option<u8>, u16),
+    /// (チャンネル, 半音の 1/12 単位のずれ, セント単位の微調整)
+    Tuning(u8, i32, i32),
+    /// (チャンネル, temperaments の番号, 主音の音名)
+    Temperament(u8, u8, u8),
+    /// 基準ピッチ (A4 の周波数)。全てのチャンネルに効く
+    ConcertPitch(u16),
     HoldOn(u8),
     HoldOff(u8),
     ModulationOn(u8),
@@ -16,4 +37,282 @@ pub enum Event {
     ReverbOff(u8),
     ChorusOn(u8),
     ChorusOff(u8),
+    RecordingOn,
+    RecordingOff,
+    /// 演奏の SMF への書き出しを区切る
+    SplitMidiFile,
+    /// 直近の演奏を SMF に書き出す
+    DumpRollingBuffer,
+    BackingTrack(Transport),
+}
+
+/// 伴奏の再生操作
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    Start,
+    Stop,
+    Rewind,
+}
+
+/// 操作方式 (v1 / v2 / v3) ごとの入力の解釈
+pub trait Scheme {
+    /// status は入力を受け取った時点のバックエンドの状態
+    fn handle(
+        &mut self,
+        settings: &mut SynthesizerSettings,
+        status: SynthStatus,
+        idx: usize,
+        ev: kmctrler::Event,
+    ) -> Option<Event>;
+
+    /// 1 つの入力から複数のイベントを出すときの残りと、その前に空ける時間
+    ///
+    /// ここから出るノートは全て操作の合図の音として扱う
+    fn pop_event_queue(&mut self) -> Option<(Event, Duration)>;
+
+    /// 出力したイベントをバックエンドが処理できたかを受け取る
+    fn processed(&mut self, _settings: &mut SynthesizerSettings, _ev: Event, _ok: bool) {}
+}
+
+/// SynthCtrler が出力するイベント
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Output {
+    pub event: Event,
+    /// 操作の合図として鳴らすだけの音。演奏としては記録しない
+    pub cue: bool,
+}
+
+impl Output {
+    fn play(event: Event) -> Self {
+        Self { event, cue: false }
+    }
+}
+
+/// 調整モードの合図で、音を止める前に鳴らしておく時間
+pub const CUE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// ノート番号を MIDI の範囲 (0..=127) に収める。範囲外の音はオクターブ単位で折り返す
+pub fn fold_note(note: i32) -> u8 {
+    let mut note = note;
+    while note > 127 {
+        note -= 12;
+    }
+    while note < 0 {
+        note += 12;
+    }
+    note as u8
+}
+
+/// 操作方式によらず、出力する全てのノートを fold_note で範囲に収める
+fn keep_in_range(ev: Event) -> Event {
+    match ev {
+        Event::Noteon(chan, key, vel) => Event::Noteon(chan, fold_note(key as i32), vel),
+        Event::Noteoff(chan, key) => Event::Noteoff(chan, fold_note(key as i32)),
+        ev => ev,
+    }
+}
+
+/// 鍵盤を分けたキーボードでは、チャンネル全体にかかる操作を左側のチャンネルにも送る
+fn mirror(settings: &SynthesizerSettings, ev: Event) -> Option<Event> {
+    let split = |chan| settings.split_channel(chan);
+    Some(match ev {
+        Event::AllNotesOff(chan) => Event::AllNotesOff(split(chan)?),
+        Event::BankSelect(chan, soundfont, bank) => {
+            Event::BankSelect(split(chan)?, soundfont, bank)
+        }
+        Event::Tuning(chan, tuning, fine_tune) => Event::Tuning(split(chan)?, tuning, fine_tune),
+        Event::Temperament(chan, temperament, root) => {
+            Event::Temperament(split(chan)?, temperament, root)
+        }
+        Event::HoldOn(chan) => Event::HoldOn(split(chan)?),
+        Event::HoldOff(chan) => Event::HoldOff(split(chan)?),
+        Event::ModulationOn(chan) => Event::ModulationOn(split(chan)?),
+        Event::ModulationOff(chan) => Event::ModulationOff(split(chan)?),
+        Event::ReverbOn(chan) => Event::ReverbOn(split(chan)?),
+        Event::ReverbOff(chan) => Event::ReverbOff(split(chan)?),
+        Event::ChorusOn(chan) => Event::ChorusOn(split(chan)?),
+        Event::ChorusOff(chan) => Event::ChorusOff(split(chan)?),
+        _ => return None,
+    })
+}
+
+/// チャンネルの音色と効果をキーボードの設定に合わせる
+pub fn channel_setup(chan: u8, program_no: u8, keyboard: &KeyboardSettings) -> [Event; 6] {
+    [
+        Event::BankSelect(chan, keyboard.soundfont(), keyboard.bank()),
+        Event::ProgramChange(chan, program_no),
+        Event::Temperament(chan, keyboard.temperament(), keyboard.temperament_root()),
+        Event::Tuning(chan, keyboard.tuning(), keyboard.fine_tune()),
+        if keyboard.reverb() {
+            Event::ReverbOn(chan)
+        } else {
+            Event::ReverbOff(chan)
+        },
+        if keyboard.chorus() {
+            Event::ChorusOn(chan)
+        } else {
+            Event::ChorusOff(chan)
+        },
+    ]
+}
+
+fn new_scheme(control_scheme: ControlScheme) -> Box<dyn Scheme> {
+    match control_scheme {
+        ControlScheme::V1 => Box::<v1::SynthCtrler>::default(),
+        ControlScheme::V2 => Box::<v2::SynthCtrler>::default(),
+        ControlScheme::V3 => Box::<v3::SynthCtrler>::default(),
+    }
+}
+
+/// キーボードごとに設定された操作方式へ入力を振り分ける
+///
+/// 同じ操作方式のキーボードは状態 (調整モードなど) を共有する
+pub struct SynthCtrler {
+    rx: mpsc::Receiver<(usize, kmctrler::Event)>,
+    settings: SynthesizerSettings,
+    schemes: HashMap<ControlScheme, Box<dyn Scheme>>,
+    /// mirror で左側のチャンネルに送る残り
+    mirrored: Vec<Event>,
+}
+
+impl SynthCtrler {
+    pub fn new(
+        settings: SynthesizerSettings,
+        rx: mpsc::Receiver<(usize, kmctrler::Event)>,
+    ) -> Self {
+        Self {
+            rx,
+            settings,
+            schemes: HashMap::new(),
+            mirrored: Vec::new(),
+        }
+    }
+
+    /// 操作方式によらず、出力する全てのイベントに通す
+    fn output(&mut self, ev: Event) -> Event {
+        let ev = keep_in_range(ev);
+        self.mirrored.extend(mirror(&self.settings, ev));
+        ev
+    }
+
+    fn pop_event_queue(&mut self) -> Option<(Output, Duration)> {
+        if let Some(event) = self.mirrored.pop() {
+            return Some((Output::play(event), Duration::ZERO));
+        }
+        let (event, delay) = self
+            .schemes
+            .values_mut()
+            .find_map(|scheme| scheme.pop_event_queue())?;
+        let event = self.output(event);
+        let cue = matches!(event, Event::Noteon(..) | Event::Noteoff(..));
+        Some((Output { event, cue }, delay))
+    }
+
+    fn handle(
+        &mut self,
+        synth: &dyn SynthBackend,
+        idx: usize,
+        ev: kmctrler::Event,
+    ) -> Option<Event> {
+        let control_scheme = self.settings.control_scheme(idx as u8);
+        let event = self
+            .schemes
+            .entry(control_scheme)
+            .or_insert_with(|| new_scheme(control_scheme))
+            .handle(&mut self.settings, synth.status(), idx, ev)?;
+        Some(self.output(event))
+    }
+
+    /// 操作方式が出力の合間に入れる待ち時間は、実際に待ってから返す
+    ///
+    /// synth は切り替え先を決めるために状態を見るだけで、イベントは送らない
+    pub fn recv(&mut self, synth: &dyn SynthBackend) -> Result<Output, RecvError> {
+        loop {
+            if let Some((output, delay)) = self.pop_event_queue() {
+                sleep(delay);
+                return Ok(output);
+            }
+            let (idx, ev) = self.rx.recv()?;
+            if let Some(event) = self.handle(synth, idx, ev) {
+                return Ok(Output::play(event));
+            }
+        }
+    }
+
+    /// 受信済みの入力だけを処理し、出力が無ければ TryRecvError::Empty を返す
+    ///
+    /// 待ち時間は待たずに、イベントの前に空けるべき時間として返す
+    pub fn try_recv(
+        &mut self,
+        synth: &dyn SynthBackend,
+    ) -> Result<(Duration, Output), TryRecvError> {
+        loop {
+            if let Some((output, delay)) = self.pop_event_queue() {
+                return Ok((delay, output));
+            }
+            let (idx, ev) = self.rx.try_recv()?;
+            if let Some(event) = self.handle(synth, idx, ev) {
+                return Ok((Duration::ZERO, Output::play(event)));
+            }
+        }
+    }
+
+    /// recv で受け取ったイベントを送った結果を知らせる。合図の音はこれを見て選ぶ
+    pub fn processed(&mut self, ev: Event, ok: bool) {
+        for scheme in self.schemes.values_mut() {
+            scheme.processed(&mut self.settings, ev, ok);
+        }
+    }
+
+    pub fn settings(&self) -> &SynthesizerSettings {
+        &self.settings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{kmctrler::Input, synth_backend::Recorder};
+
+    #[test]
+    fn cue_notes_are_tagged() {
+        let mut settings = SynthesizerSettings::ephemeral();
+        settings.set_default_control_scheme(ControlScheme::V3);
+        let (tx, rx) = mpsc::channel();
+        let mut ctrler = SynthCtrler::new(settings, rx);
+        for ev in [
+            kmctrler::Event::Press(Input::Select),
+            kmctrler::Event::Press(Input::Start),
+            kmctrler::Event::Release(Input::Select),
+            kmctrler::Event::Release(Input::Start),
+            kmctrler::Event::Press(Input::Key(18)),
+            kmctrler::Event::Press(Input::Key(0)),
+        ] {
+            tx.send((0, ev)).unwrap();
+        }
+        let recorder = Recorder::default();
+        let mut outputs = Vec::new();
+        while let Ok((_, output)) = ctrler.try_recv(&recorder) {
+            ctrler.processed(output.event, recorder.process(output.event));
+            outputs.push((output.event, output.cue));
+        }
+        assert_eq!(
+            outputs,
+            [
+                (Event::ModulationOn(0), false),
+                (Event::Noteon(9, 42, 127), true),
+                (Event::Noteoff(9, 42), true),
+                (Event::Noteon(9, 42, 127), true),
+                (Event::Noteoff(9, 42), true),
+                (Event::RecordingOn, false),
+                (Event::Noteon(0, 72, 100), true),
+                (Event::Noteoff(0, 72), true),
+                (Event::Noteon(0, 76, 100), true),
+                (Event::Noteoff(0, 76), true),
+                (Event::Noteon(0, 79, 100), true),
+                (Event::Noteoff(0, 79), true),
+                (Event::Noteon(0, 60, 100), false),
+            ]
+        );
+    }
 }
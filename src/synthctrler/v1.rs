@@ -1,14 +1,15 @@
-use std::{
-    collections::HashMap,
-    sync::mpsc::{self, RecvError},
-};
+use std::{collections::HashMap, time::Duration};
 
 use crate::{
     kmctrler::{self, Input},
     settings::SynthesizerSettings,
+    synth_backend::SynthStatus,
 };
 
-use super::Event;
+use super::{
+    v2::{disconnect, midi_action, part, virtual_key},
+    Event, Scheme,
+};
 
 pub fn toggle_reverb(settings: &mut SynthesizerSettings, chan: u8) -> Event {
     let keyboard = settings.get_or_create_keyboard_mut(chan);
@@ -22,6 +23,14 @@ pub fn toggle_reverb(settings: &mut SynthesizerSettings, chan: u8) -> Event {
     }
 }
 
+pub fn set_tuning(settings: &mut SynthesizerSettings, chan: u8, tuning: i32) -> Event {
+    let keyboard = settings.get_or_create_keyboard_mut(chan);
+    keyboard.set_tuning(tuning);
+    let fine_tune = keyboard.fine_tune();
+    settings.queue_save();
+    Event::Tuning(chan, tuning, fine_tune)
+}
+
 pub fn toggle_chorus(settings: &mut SynthesizerSettings, chan: u8) -> Event {
     let keyboard = settings.get_or_create_keyboard_mut(chan);
     let new_chorus = !keyboard.chorus();
@@ -34,9 +43,8 @@ pub fn toggle_chorus(settings: &mut SynthesizerSettings, chan: u8) -> Event {
     }
 }
 
-/// キーボード全体演奏と排他
-///   チューニング .... Select + Start + Key
 /// キーボードごと演奏と排他
+///   チューニング .... Select + Start + Key
 ///   プログラムチェンジ .... Start + Key
 ///   オクターブシフト .... Select + Key
 ///   リバーブ(toggle) .... Select + Start + WheelUp
@@ -44,30 +52,22 @@ pub fn toggle_chorus(settings: &mut SynthesizerSettings, chan: u8) -> Event {
 /// キーボードごと演奏と同時
 ///   ホールド .... WheelDown
 ///   モジュレーション(ビブラート) .... WheelUp
+#[derive(Default)]
 pub struct SynthCtrler {
-    rx: mpsc::Receiver<(usize, kmctrler::Event)>,
-    settings: SynthesizerSettings,
     buf_programs: HashMap<u8, u8>,
     buf_select: u32,
     buf_start: u32,
+    midi_keydown_table: HashMap<(u8, u8), u8>,
+    event_queue: Vec<Event>,
 }
 
 impl SynthCtrler {
-    #[allow(unused)]
-    pub fn new(
-        settings: SynthesizerSettings,
-        rx: mpsc::Receiver<(usize, kmctrler::Event)>,
-    ) -> Self {
-        Self {
-            rx,
-            settings,
-            buf_programs: HashMap::new(),
-            buf_select: 0,
-            buf_start: 0,
-        }
-    }
-
-    fn program_change(&mut self, chan: u8, key: u8) -> Option<Event> {
+    fn program_change(
+        &mut self,
+        settings: &mut SynthesizerSettings,
+        chan: u8,
+        key: u8,
+    ) -> Option<Event> {
         let bit = match key {
             0 => 0b_01000000,
             2 => 0b_00100000,
@@ -81,14 +81,19 @@ impl SynthCtrler {
         self.buf_programs
             .insert(chan, self.buf_programs.get(&chan).unwrap_or(&0) | bit);
         let program_no = self.buf_programs[&chan] - 1;
-        self.settings
+        settings
             .get_or_create_keyboard_mut(chan)
             .set_program_no(program_no);
-        self.settings.queue_save();
+        settings.queue_save();
         Some(Event::ProgramChange(chan, program_no))
     }
 
-    fn octave_change(&mut self, chan: u8, key: u8) -> Option<Event> {
+    fn octave_change(
+        &mut self,
+        settings: &mut SynthesizerSettings,
+        chan: u8,
+        key: u8,
+    ) -> Option<Event> {
         let octave = match key {
             0 => 0,
             2 => 1,
@@ -101,71 +106,78 @@ impl SynthCtrler {
             14 => 8,
             _ => return None,
         };
-        self.settings
-            .get_or_create_keyboard_mut(chan)
-            .set_octave(octave);
-        self.settings.queue_save();
+        settings.get_or_create_keyboard_mut(chan).set_octave(octave);
+        settings.queue_save();
         Some(Event::AllNotesOff(chan))
     }
+}
 
-    #[allow(unused)]
-    pub fn recv(&mut self) -> Result<Event, RecvError> {
-        loop {
-            let (idx, ev) = self.rx.recv()?;
-            let chan = idx as u8;
-            match ev {
-                kmctrler::Event::Press(Input::Key(key)) => {
-                    if self.buf_start >> chan & 0x01 != 0 && self.buf_select >> chan & 0x01 != 0 {
-                        return Ok(Event::Tuning(key as i32 - 12));
-                    }
-                    if self.buf_start >> chan & 0x01 != 0 {
-                        let Some(event) = self.program_change(chan, key) else {
-                            continue;
-                        };
-                        return Ok(event);
-                    }
-                    if self.buf_select >> chan & 0x01 != 0 {
-                        let Some(event) = self.octave_change(chan, key) else {
-                            continue;
-                        };
-                        return Ok(event);
-                    }
-                    return Ok(Event::Noteon(
-                        chan,
-                        key + self.settings.get_or_create_keyboard(chan).octave() * 12,
-                        127,
-                    ));
+impl Scheme for SynthCtrler {
+    fn handle(
+        &mut self,
+        settings: &mut SynthesizerSettings,
+        _status: SynthStatus,
+        idx: usize,
+        ev: kmctrler::Event,
+    ) -> Option<Event> {
+        let chan = idx as u8;
+        match ev {
+            kmctrler::Event::Press(Input::Key(key)) => {
+                if self.buf_start >> chan & 0x01 != 0 && self.buf_select >> chan & 0x01 != 0 {
+                    return Some(set_tuning(settings, chan, key as i32 - 12));
                 }
-                kmctrler::Event::Release(Input::Key(key)) => {
-                    self.buf_programs.remove(&chan);
-                    return Ok(Event::Noteoff(
-                        chan,
-                        key + self.settings.get_or_create_keyboard(chan).octave() * 12,
-                    ));
+                if self.buf_start >> chan & 0x01 != 0 {
+                    return self.program_change(settings, chan, key);
                 }
-                kmctrler::Event::Press(Input::WheelUp) => {
-                    if self.buf_start >> chan & 0x01 != 0 && self.buf_select >> chan & 0x01 != 0 {
-                        return Ok(toggle_reverb(&mut self.settings, chan));
-                    }
-                    return Ok(Event::ModulationOn(chan));
+                if self.buf_select >> chan & 0x01 != 0 {
+                    return self.octave_change(settings, chan, key);
                 }
-                kmctrler::Event::Release(Input::WheelUp) => return Ok(Event::ModulationOff(chan)),
-                kmctrler::Event::Press(Input::WheelDown) => {
-                    if self.buf_start >> chan & 0x01 != 0 && self.buf_select >> chan & 0x01 != 0 {
-                        return Ok(toggle_chorus(&mut self.settings, chan));
-                    }
-                    return Ok(Event::HoldOn(chan));
+                let (part_chan, _, octave) = part(settings, chan, key);
+                let transpose = settings.get_or_create_keyboard(chan).transpose();
+                let virtual_key = virtual_key(key, octave, transpose);
+                return Some(Event::Noteon(part_chan, virtual_key, 127));
+            }
+            kmctrler::Event::Release(Input::Key(key)) => {
+                self.buf_programs.remove(&chan);
+                let (part_chan, _, octave) = part(settings, chan, key);
+                let transpose = settings.get_or_create_keyboard(chan).transpose();
+                let virtual_key = virtual_key(key, octave, transpose);
+                return Some(Event::Noteoff(part_chan, virtual_key));
+            }
+            kmctrler::Event::Press(Input::WheelUp) => {
+                if self.buf_start >> chan & 0x01 != 0 && self.buf_select >> chan & 0x01 != 0 {
+                    return Some(toggle_reverb(settings, chan));
                 }
-                kmctrler::Event::Release(Input::WheelDown) => return Ok(Event::HoldOff(chan)),
-                kmctrler::Event::Press(Input::Select) => self.buf_select |= (0x01 << chan) as u32,
-                kmctrler::Event::Release(Input::Select) => {
-                    self.buf_select &= !((0x01 << chan) as u32)
+                return Some(Event::ModulationOn(chan));
+            }
+            kmctrler::Event::Release(Input::WheelUp) => return Some(Event::ModulationOff(chan)),
+            kmctrler::Event::Press(Input::WheelDown) => {
+                if self.buf_start >> chan & 0x01 != 0 && self.buf_select >> chan & 0x01 != 0 {
+                    return Some(toggle_chorus(settings, chan));
                 }
-                kmctrler::Event::Press(Input::Start) => self.buf_start |= (0x01 << chan) as u32,
-                kmctrler::Event::Release(Input::Start) => {
-                    self.buf_start &= !((0x01 << chan) as u32)
-                }
-            };
-        }
+                return Some(Event::HoldOn(chan));
+            }
+            kmctrler::Event::Release(Input::WheelDown) => return Some(Event::HoldOff(chan)),
+            kmctrler::Event::Press(Input::Select) => self.buf_select |= (0x01 << chan) as u32,
+            kmctrler::Event::Release(Input::Select) => self.buf_select &= !((0x01 << chan) as u32),
+            kmctrler::Event::Press(Input::Start) => self.buf_start |= (0x01 << chan) as u32,
+            kmctrler::Event::Release(Input::Start) => self.buf_start &= !((0x01 << chan) as u32),
+            // 押したときのオクターブと移調で離す
+            kmctrler::Event::NoteOn(_, _) | kmctrler::Event::NoteOff(_) => {
+                return midi_action(settings, &mut self.midi_keydown_table, chan, &ev);
+            }
+            kmctrler::Event::Disconnect => {
+                self.buf_programs.remove(&chan);
+                self.buf_select &= !((0x01 << chan) as u32);
+                self.buf_start &= !((0x01 << chan) as u32);
+                self.midi_keydown_table.retain(|&(x, _), _| x != chan);
+                return Some(disconnect(&mut self.event_queue, chan));
+            }
+        };
+        None
+    }
+
+    fn pop_event_queue(&mut self) -> Option<(Event, Duration)> {
+        self.event_queue.pop().map(|event| (event, Duration::ZERO))
     }
 }
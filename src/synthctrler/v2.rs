@@ -1,18 +1,18 @@
-use std::{
-    collections::HashMap,
-    sync::mpsc::{self, RecvError},
-    thread::sleep,
-    time::Duration,
-};
+use std::{collections::HashMap, time::Duration};
 
 use crate::{
     kmctrler::{self, Input},
-    settings::{KeyboardSettings, SynthesizerSettings},
+    settings::{
+        split_channel_of, KeyboardSettings, SynthesizerSettings, A4_HZ_RANGE, FINE_TUNE_LIMIT,
+        MAX_OCTAVE, TRANSPOSE_LIMIT,
+    },
+    synth_backend::SynthStatus,
 };
 
 use super::{
-    v1::{toggle_chorus, toggle_reverb},
-    Event,
+    channel_setup, fold_note,
+    v1::{set_tuning, toggle_chorus, toggle_reverb},
+    Event, Scheme, CUE_INTERVAL,
 };
 
 fn noteon(chan: u8, virtual_key: u8, keyboard: &KeyboardSettings) -> Event {
@@ -20,6 +20,32 @@ fn noteon(chan: u8, virtual_key: u8, keyboard: &KeyboardSettings) -> Event {
     Event::Noteon(chan, virtual_key, vel)
 }
 
+/// MIDI キーボードのノート番号にオクターブと移調の設定を反映する。オクターブ 5 でそのままの音程になる
+pub fn midi_note(note: u8, octave: u8, transpose: i8) -> u8 {
+    fold_note(note as i32 + (octave as i32 - 5) * 12 + transpose as i32)
+}
+
+/// コントローラーの鍵盤の番号にオクターブと移調の設定を反映する
+pub fn virtual_key(key: u8, octave: u8, transpose: i8) -> u8 {
+    fold_note(key as i32 + octave as i32 * 12 + transpose as i32)
+}
+
+/// 鍵盤を分けたキーボードの左側の鍵盤なら (左側のチャンネル, プログラム番号, オクターブ)、
+/// そうでなければキーボード本来の値を返す
+pub fn part(settings: &mut SynthesizerSettings, chan: u8, key: u8) -> (u8, u8, u8) {
+    settings.get_or_create_keyboard(chan);
+    let split_channel = settings.split_channel(chan);
+    let keyboard = &settings.keyboards()[chan as usize];
+    match (split_channel, keyboard.split_point()) {
+        (Some(split_channel), Some(split_point)) if key < split_point => (
+            split_channel,
+            keyboard.split_program_no(),
+            keyboard.split_octave(),
+        ),
+        _ => (chan, keyboard.program_no(), keyboard.octave()),
+    }
+}
+
 fn key_to_program_no(keys: &[bool; 24]) -> u8 {
     keys[5] as u8 * 0b01000000
         + keys[6] as u8 * 0b00100000
@@ -62,13 +88,74 @@ pub fn octave_shift_down(settings: &mut SynthesizerSettings, chan: u8) {
 
 pub fn octave_shift_up(settings: &mut SynthesizerSettings, chan: u8) {
     let keyboard = settings.get_or_create_keyboard_mut(chan);
-    if keyboard.octave() >= 9 {
+    if keyboard.octave() >= MAX_OCTAVE {
         return;
     }
     keyboard.set_octave(keyboard.octave() + 1);
     settings.queue_save();
 }
 
+fn fine_tune(settings: &mut SynthesizerSettings, chan: u8, delta: i32) -> Event {
+    let keyboard = settings.get_or_create_keyboard_mut(chan);
+    let fine_tune = (keyboard.fine_tune() + delta).clamp(-FINE_TUNE_LIMIT, FINE_TUNE_LIMIT);
+    keyboard.set_fine_tune(fine_tune);
+    let tuning = keyboard.tuning();
+    settings.queue_save();
+    Event::Tuning(chan, tuning, fine_tune)
+}
+
+/// 基準ピッチを 1Hz ずつ上げ下げする
+fn nudge_a4_hz(settings: &mut SynthesizerSettings, up: bool) -> Event {
+    let a4_hz = if up {
+        settings.a4_hz() + 1
+    } else {
+        settings.a4_hz() - 1
+    }
+    .clamp(*A4_HZ_RANGE.start(), *A4_HZ_RANGE.end());
+    settings.set_a4_hz(a4_hz);
+    settings.queue_save();
+    Event::ConcertPitch(a4_hz)
+}
+
+fn temperament_event(settings: &mut SynthesizerSettings, chan: u8) -> Event {
+    let keyboard = settings.get_or_create_keyboard(chan);
+    Event::Temperament(chan, keyboard.temperament(), keyboard.temperament_root())
+}
+
+/// 音律を設定の temperaments の順に切り替える
+fn cycle_temperament(settings: &mut SynthesizerSettings, chan: u8, forward: bool) -> Event {
+    let len = settings.temperaments().len() as u8;
+    let keyboard = settings.get_or_create_keyboard_mut(chan);
+    let current = keyboard.temperament().min(len - 1);
+    keyboard.set_temperament(if forward {
+        (current + 1) % len
+    } else {
+        current.checked_sub(1).unwrap_or(len - 1)
+    });
+    settings.queue_save();
+    temperament_event(settings, chan)
+}
+
+fn set_temperament_root(settings: &mut SynthesizerSettings, chan: u8, root: u8) -> Event {
+    settings
+        .get_or_create_keyboard_mut(chan)
+        .set_temperament_root(root);
+    settings.queue_save();
+    temperament_event(settings, chan)
+}
+
+fn transpose(settings: &mut SynthesizerSettings, chan: u8, up: bool) {
+    let keyboard = settings.get_or_create_keyboard_mut(chan);
+    let transpose = if up {
+        keyboard.transpose() + 1
+    } else {
+        keyboard.transpose() - 1
+    }
+    .clamp(-TRANSPOSE_LIMIT, TRANSPOSE_LIMIT);
+    keyboard.set_transpose(transpose);
+    settings.queue_save();
+}
+
 fn program_change(settings: &mut SynthesizerSettings, chan: u8, program_no: u8) {
     settings
         .get_or_create_keyboard_mut(chan)
@@ -76,6 +163,113 @@ fn program_change(settings: &mut SynthesizerSettings, chan: u8, program_no: u8)
     settings.queue_save();
 }
 
+/// 左側の音色とオクターブで A を鳴らす。離す方は event_queue に積む
+fn split_cue(event_queue: &mut Vec<Event>, split: u8, keyboard: &KeyboardSettings) -> Event {
+    let cue = virtual_key(9, keyboard.split_octave(), keyboard.transpose());
+    event_queue.push(Event::Noteoff(split, cue));
+    let vel = keyboard.velocity_per_program()[keyboard.split_program_no() as usize];
+    Event::Noteon(split, cue, vel)
+}
+
+/// 鍵盤を分ける位置と左側の音色 (C#3 + Start)
+fn split_action(
+    settings: &mut SynthesizerSettings,
+    event_queue: &mut Vec<Event>,
+    chan: u8,
+    ev: &kmctrler::Event,
+) -> Result<Event, bool> {
+    match ev {
+        // 押した鍵盤から右を元の音色にする。C3 で分けるのをやめる
+        kmctrler::Event::Press(Input::Key(key)) if *key != 1 => {
+            let Some(split) = split_channel_of(chan) else {
+                eprintln!("keyboard {} cannot be split", chan);
+                return Err(true);
+            };
+            let was_split = settings.split_channel(chan).is_some();
+            settings
+                .get_or_create_keyboard_mut(chan)
+                .set_split_point((*key > 0).then_some(*key));
+            settings.queue_save();
+            let keyboard = settings.get_or_create_keyboard(chan);
+            if *key == 0 {
+                add_off_sfx(event_queue, chan, keyboard);
+                // 左側で鳴っている音を止める
+                return Ok(Event::AllNotesOff(split));
+            }
+            // 分けた位置の右側の最初の音を鳴らす
+            let cue = virtual_key(*key, keyboard.octave(), keyboard.transpose());
+            event_queue.push(Event::Noteoff(chan, cue));
+            if was_split {
+                return Ok(noteon(chan, cue, keyboard));
+            }
+            event_queue.push(noteon(chan, cue, keyboard));
+            // 左側のチャンネルの音色と効果を合わせてから鳴らす
+            let mut setup = channel_setup(split, keyboard.split_program_no(), keyboard).to_vec();
+            setup.reverse();
+            let first = setup.pop().unwrap();
+            event_queue.append(&mut setup);
+            Ok(first)
+        }
+        kmctrler::Event::Press(Input::WheelUp | Input::WheelDown) => {
+            let keyboard = settings.get_or_create_keyboard_mut(chan);
+            let program_no = if matches!(ev, kmctrler::Event::Press(Input::WheelUp)) {
+                (keyboard.split_program_no() + 1) % 128
+            } else {
+                keyboard.split_program_no().checked_sub(1).unwrap_or(127)
+            };
+            keyboard.set_split_program_no(program_no);
+            settings.queue_save();
+            let split = settings.split_channel(chan).ok_or(true)?;
+            let cue = split_cue(event_queue, split, settings.get_or_create_keyboard(chan));
+            event_queue.push(cue);
+            Ok(Event::ProgramChange(split, program_no))
+        }
+        _ => Err(true),
+    }
+}
+
+/// 左側のオクターブ (C#3 + Select)
+fn split_octave_action(
+    settings: &mut SynthesizerSettings,
+    event_queue: &mut Vec<Event>,
+    chan: u8,
+    ev: &kmctrler::Event,
+) -> Result<Event, bool> {
+    let keyboard = settings.get_or_create_keyboard_mut(chan);
+    let octave = match ev {
+        kmctrler::Event::Press(Input::WheelUp) => (keyboard.split_octave() + 1).min(MAX_OCTAVE),
+        kmctrler::Event::Press(Input::WheelDown) => keyboard.split_octave().saturating_sub(1),
+        _ => return Err(true),
+    };
+    keyboard.set_split_octave(octave);
+    settings.queue_save();
+    let split = settings.split_channel(chan).ok_or(true)?;
+    Ok(split_cue(
+        event_queue,
+        split,
+        settings.get_or_create_keyboard(chan),
+    ))
+}
+
+/// 合図の音を 1 つずつ取り出す。音を止める前には CUE_INTERVAL だけ鳴らしておく
+pub fn pop_cue(event_queue: &mut Vec<Event>) -> Option<(Event, Duration)> {
+    let event = event_queue.pop()?;
+    let delay = match event {
+        Event::Noteoff(_, _) => CUE_INTERVAL,
+        _ => Duration::ZERO,
+    };
+    Some((event, delay))
+}
+
+/// 調整モードで鳴らす音は、合図として event_queue から出す
+pub fn cue(event_queue: &mut Vec<Event>, event: Event) -> Option<Event> {
+    if matches!(event, Event::Noteon(..) | Event::Noteoff(..)) {
+        event_queue.push(event);
+        return None;
+    }
+    Some(event)
+}
+
 pub fn percussion(event_queue: &mut Vec<Event>, no: i32) -> Event {
     if no == 1 {
         event_queue.push(Event::Noteoff(9, 42));
@@ -90,7 +284,14 @@ pub fn percussion(event_queue: &mut Vec<Event>, no: i32) -> Event {
     }
 }
 
-fn add_on_sfx(event_queue: &mut Vec<Event>, chan: u8, keyboard: &KeyboardSettings) {
+/// 押されたままの音とホールド・モジュレーションを解除する
+pub fn disconnect(event_queue: &mut Vec<Event>, chan: u8) -> Event {
+    event_queue.push(Event::AllNotesOff(chan));
+    event_queue.push(Event::ModulationOff(chan));
+    Event::HoldOff(chan)
+}
+
+pub fn add_on_sfx(event_queue: &mut Vec<Event>, chan: u8, keyboard: &KeyboardSettings) {
     event_queue.push(Event::Noteoff(chan, 79));
     event_queue.push(noteon(chan, 79, keyboard));
     event_queue.push(Event::Noteoff(chan, 76));
@@ -99,7 +300,7 @@ fn add_on_sfx(event_queue: &mut Vec<Event>, chan: u8, keyboard: &KeyboardSetting
     event_queue.push(noteon(chan, 72, keyboard));
 }
 
-fn add_off_sfx(event_queue: &mut Vec<Event>, chan: u8, keyboard: &KeyboardSettings) {
+pub fn add_off_sfx(event_queue: &mut Vec<Event>, chan: u8, keyboard: &KeyboardSettings) {
     event_queue.push(Event::Noteoff(chan, 72));
     event_queue.push(noteon(chan, 72, keyboard));
     event_queue.push(Event::Noteoff(chan, 76));
@@ -139,6 +340,12 @@ pub fn config_mode_action(
 ) -> Result<Event, bool> {
     // C#3
     if state.keys()[1] {
+        if state.start() {
+            return split_action(settings, event_queue, chan, ev);
+        }
+        if state.select() {
+            return split_octave_action(settings, event_queue, chan, ev);
+        }
         match ev {
             kmctrler::Event::Press(Input::Key(1)) => {
                 let keyboard = settings.get_or_create_keyboard(chan);
@@ -148,8 +355,7 @@ pub fn config_mode_action(
                     .filter(|i| ((program_no + 1) >> i) & 1 == 1)
                     .map(|i| 11 - i)
                     .flat_map(|key| {
-                        let octave = keyboard.octave();
-                        let virtual_key = key + octave * 12;
+                        let virtual_key = virtual_key(key, keyboard.octave(), keyboard.transpose());
                         [
                             Event::Noteoff(chan, virtual_key),
                             noteon(chan, virtual_key, keyboard),
@@ -165,8 +371,7 @@ pub fn config_mode_action(
                     let program_no = key_to_program_no(state.keys());
                     program_change(settings, chan, program_no);
                     let keyboard = settings.get_or_create_keyboard(chan);
-                    let octave = keyboard.octave();
-                    let virtual_key = key + octave * 12;
+                    let virtual_key = virtual_key(*key, keyboard.octave(), keyboard.transpose());
                     event_queue.push(Event::Noteoff(chan, virtual_key));
                     event_queue.push(noteon(chan, virtual_key, keyboard));
                     return Ok(Event::ProgramChange(chan, program_no));
@@ -196,10 +401,36 @@ pub fn config_mode_action(
         }
     }
     if state.start() {
-        if let kmctrler::Event::Press(Input::Key(key)) = ev {
-            return Ok(Event::Tuning(*key as i32 - 12));
-        }
-        return Err(true);
+        let delta = match ev {
+            kmctrler::Event::Press(Input::Key(key)) => {
+                return Ok(set_tuning(settings, chan, *key as i32 - 12));
+            }
+            kmctrler::Event::Press(Input::WheelDown) => -1,
+            kmctrler::Event::Press(Input::WheelUp) => 1,
+            _ => return Err(true),
+        };
+        let event = fine_tune(settings, chan, delta);
+        event_queue.push(Event::Noteoff(chan, 69));
+        event_queue.push(noteon(chan, 69, settings.get_or_create_keyboard(chan)));
+        return Ok(event);
+    }
+    if state.select() {
+        let event = match ev {
+            kmctrler::Event::Press(Input::Key(key)) => {
+                let event = set_temperament_root(settings, chan, key % 12);
+                let keyboard = settings.get_or_create_keyboard(chan);
+                let virtual_key = virtual_key(*key, keyboard.octave(), keyboard.transpose());
+                event_queue.push(Event::Noteoff(chan, virtual_key));
+                event_queue.push(noteon(chan, virtual_key, keyboard));
+                return Ok(event);
+            }
+            kmctrler::Event::Press(Input::WheelDown) => cycle_temperament(settings, chan, false),
+            kmctrler::Event::Press(Input::WheelUp) => cycle_temperament(settings, chan, true),
+            _ => return Err(true),
+        };
+        event_queue.push(Event::Noteoff(chan, 69));
+        event_queue.push(noteon(chan, 69, settings.get_or_create_keyboard(chan)));
+        return Ok(event);
     }
     match ev {
         kmctrler::Event::Press(Input::Key(13)) => {
@@ -220,29 +451,77 @@ pub fn config_mode_action(
             }
             return Ok(toggle_chorus(settings, chan));
         }
+        kmctrler::Event::Press(Input::WheelUp | Input::WheelDown) => {
+            transpose(
+                settings,
+                chan,
+                matches!(ev, kmctrler::Event::Press(Input::WheelUp)),
+            );
+            // 移調した A4 を鳴らす
+            let keyboard = settings.get_or_create_keyboard(chan);
+            let cue = (69 + keyboard.transpose() as i32) as u8;
+            event_queue.push(Event::Noteoff(chan, cue));
+            return Ok(noteon(chan, cue, keyboard));
+        }
+        // G#3, A#3
+        kmctrler::Event::Press(Input::Key(key @ (8 | 10))) => {
+            let event = nudge_a4_hz(settings, *key == 10);
+            event_queue.push(Event::Noteoff(chan, 69));
+            event_queue.push(noteon(chan, 69, settings.get_or_create_keyboard(chan)));
+            return Ok(event);
+        }
         _ => {}
     }
     Err(false)
 }
 
+pub fn midi_action(
+    settings: &mut SynthesizerSettings,
+    midi_keydown_table: &mut HashMap<(u8, u8), u8>,
+    chan: u8,
+    ev: &kmctrler::Event,
+) -> Option<Event> {
+    match ev {
+        kmctrler::Event::NoteOn(note, vel) => {
+            let keyboard = settings.get_or_create_keyboard(chan);
+            let virtual_key = midi_note(*note, keyboard.octave(), keyboard.transpose());
+            midi_keydown_table.insert((chan, *note), virtual_key);
+            Some(Event::Noteon(chan, virtual_key, *vel))
+        }
+        kmctrler::Event::NoteOff(note) => {
+            let virtual_key = midi_keydown_table.remove(&(chan, *note))?;
+            Some(Event::Noteoff(chan, virtual_key))
+        }
+        _ => None,
+    }
+}
+
+/// キーボードごと・鍵盤ごとに、鳴らしている音のチャンネル・オクターブ・移調
+pub type KeydownTable = HashMap<u8, [Option<(u8, u8, i8)>; 24]>;
+
 pub fn common_action(
     settings: &mut SynthesizerSettings,
-    keydown_octave_table: &mut HashMap<u8, [u8; 24]>,
+    keydown_octave_table: &mut KeydownTable,
     chan: u8,
     ev: &kmctrler::Event,
 ) -> Option<Event> {
     match ev {
         kmctrler::Event::Press(Input::Key(key)) => {
+            let (part_chan, program_no, octave) = part(settings, chan, *key);
             let keyboard = settings.get_or_create_keyboard(chan);
-            let octave = keyboard.octave();
-            keydown_octave_table.entry(chan).or_default()[*key as usize] = octave;
-            let virtual_key = key + octave * 12;
-            Some(noteon(chan, virtual_key, keyboard))
+            let transpose = keyboard.transpose();
+            keydown_octave_table.entry(chan).or_insert([None; 24])[*key as usize] =
+                Some((part_chan, octave, transpose));
+            let virtual_key = virtual_key(*key, octave, transpose);
+            let vel = keyboard.velocity_per_program()[program_no as usize];
+            Some(Event::Noteon(part_chan, virtual_key, vel))
         }
         kmctrler::Event::Release(Input::Key(key)) => {
-            let octave = keydown_octave_table.entry(chan).or_default()[*key as usize];
-            let virtual_key = key + octave * 12;
-            Some(Event::Noteoff(chan, virtual_key))
+            // 押したときのチャンネル・オクターブ・移調で離す。鳴らしていない鍵盤は何もしない
+            let (part_chan, octave, transpose) =
+                keydown_octave_table.get_mut(&chan)?[*key as usize].take()?;
+            let virtual_key = virtual_key(*key, octave, transpose);
+            Some(Event::Noteoff(part_chan, virtual_key))
         }
         _ => None,
     }
@@ -255,84 +534,70 @@ pub fn common_action(
 ///   ホールド .... WheelDown
 /// 調整モード
 ///   チューニング .... Start + Key
+///   微調整 (1 セントずつ) .... Start + WheelUp / WheelDown
+///   音律の切り替え .... Select + WheelUp / WheelDown
+///   音律の主音 .... Select + Key
+///   基準ピッチ (1Hz ずつ) .... G#3 / A#3
+///   移調 (半音ずつ) .... WheelUp / WheelDown
 ///   プログラムチェンジ .... C#3 + Key / WheelUp / WheelDown
 ///   プログラムの音量の変更 .... C#3 + Key
+///   鍵盤を分ける位置 (C3 で解除) .... C#3 + Start + Key
+///   左側のプログラムチェンジ .... C#3 + Start + WheelUp / WheelDown
+///   左側のオクターブ .... C#3 + Select + WheelUp / WheelDown
 ///   リバーブ(toggle) .... C#4
 ///   コーラス(toggle) .... D#4
+#[derive(Default)]
 pub struct SynthCtrler {
-    rx: mpsc::Receiver<(usize, kmctrler::Event)>,
-    settings: SynthesizerSettings,
     mode_config: bool,
     kmctrler_states: HashMap<u8, kmctrler::State>,
-    keydown_octave_table: HashMap<u8, [u8; 24]>,
+    keydown_octave_table: KeydownTable,
+    midi_keydown_table: HashMap<(u8, u8), u8>,
     event_queue: Vec<Event>,
 }
 
-impl SynthCtrler {
-    #[allow(unused)]
-    pub fn new(
-        settings: SynthesizerSettings,
-        rx: mpsc::Receiver<(usize, kmctrler::Event)>,
-    ) -> Self {
-        Self {
-            rx,
-            settings,
-            mode_config: false,
-            kmctrler_states: HashMap::new(),
-            keydown_octave_table: HashMap::new(),
-            event_queue: Vec::new(),
-        }
-    }
+impl Scheme for SynthCtrler {
+    fn handle(
+        &mut self,
+        settings: &mut SynthesizerSettings,
+        _status: SynthStatus,
+        idx: usize,
+        ev: kmctrler::Event,
+    ) -> Option<Event> {
+        let chan = idx as u8;
+        let state = self.kmctrler_states.entry(chan).or_default();
+        state.update(&ev);
 
-    #[allow(unused)]
-    pub fn recv(&mut self) -> Result<Event, RecvError> {
-        if let Some(event) = self.event_queue.pop() {
-            if let Event::Noteoff(_, _) = event {
-                sleep(Duration::from_millis(100));
-            }
-            return Ok(event);
+        if let kmctrler::Event::Disconnect = ev {
+            self.keydown_octave_table.remove(&chan);
+            self.midi_keydown_table.retain(|&(x, _), _| x != chan);
+            return Some(disconnect(&mut self.event_queue, chan));
         }
-        loop {
-            let (idx, ev) = self.rx.recv()?;
-            let chan = idx as u8;
-            let state = self.kmctrler_states.entry(chan).or_default();
-            state.update(&ev);
-
-            if state.select() && state.start() {
-                self.mode_config = !self.mode_config;
-                state.reset_select_start();
-                return Ok(percussion(
-                    &mut self.event_queue,
-                    if self.mode_config { 1 } else { 0 },
-                ));
-            }
-            if self.mode_config {
-                match config_mode_action(
-                    &mut self.settings,
-                    &mut self.event_queue,
-                    state,
-                    chan,
-                    &ev,
-                ) {
-                    Ok(event) => return Ok(event),
-                    Err(true) => continue,
-                    Err(false) => {}
-                }
-            } else {
-                match normal_mode_action(&mut self.settings, chan, &ev) {
-                    Ok(event) => return Ok(event),
-                    Err(true) => continue,
-                    Err(false) => {}
-                }
+        if let Some(event) = midi_action(settings, &mut self.midi_keydown_table, chan, &ev) {
+            return Some(event);
+        }
+        if state.select() && state.start() {
+            self.mode_config = !self.mode_config;
+            state.reset_select_start();
+            let event = percussion(&mut self.event_queue, if self.mode_config { 1 } else { 0 });
+            return cue(&mut self.event_queue, event);
+        }
+        if self.mode_config {
+            match config_mode_action(settings, &mut self.event_queue, state, chan, &ev) {
+                Ok(event) => return cue(&mut self.event_queue, event),
+                Err(true) => return None,
+                Err(false) => {}
             }
-            if let Some(event) = common_action(
-                &mut self.settings,
-                &mut self.keydown_octave_table,
-                chan,
-                &ev,
-            ) {
-                return Ok(event);
+        } else {
+            match normal_mode_action(settings, chan, &ev) {
+                Ok(event) => return Some(event),
+                Err(true) => return None,
+                Err(false) => {}
             }
         }
+        common_action(settings, &mut self.keydown_octave_table, chan, &ev)
+    }
+
+    fn pop_event_queue(&mut self) -> Option<(Event, Duration)> {
+        pop_cue(&mut self.event_queue)
     }
 }
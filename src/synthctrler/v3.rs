@@ -1,18 +1,17 @@
-use std::{
-    collections::HashMap,
-    sync::mpsc::{self, RecvError},
-    thread::sleep,
-    time::Duration,
-};
+use std::{collections::HashMap, time::Duration};
 
 use crate::{
     kmctrler::{self, Input},
-    settings::SynthesizerSettings,
+    settings::{SynthesizerSettings, MAX_OCTAVE},
+    synth_backend::SynthStatus,
 };
 
 use super::{
-    v2::{common_action, config_mode_action, octave_shift_down, octave_shift_up, percussion},
-    Event,
+    v2::{
+        add_off_sfx, add_on_sfx, common_action, config_mode_action, cue, disconnect, midi_action,
+        octave_shift_down, octave_shift_up, percussion, pop_cue, KeydownTable,
+    },
+    Event, Scheme, Transport,
 };
 
 fn octave_shift_down_without_save(settings: &mut SynthesizerSettings, chan: u8) {
@@ -25,12 +24,31 @@ fn octave_shift_down_without_save(settings: &mut SynthesizerSettings, chan: u8)
 
 fn octave_shift_up_without_save(settings: &mut SynthesizerSettings, chan: u8) {
     let keyboard = settings.get_or_create_keyboard_mut(chan);
-    if keyboard.octave() >= 9 {
+    if keyboard.octave() >= MAX_OCTAVE {
         return;
     }
     keyboard.set_octave(keyboard.octave() + 1);
 }
 
+/// ホイールを押したまま切断したら、離したときと同じく一時的なオクターブシフトを戻す
+///
+/// 押したときに保存しているので、戻した値も保存する
+fn undo_temporary_octave_shift(
+    settings: &mut SynthesizerSettings,
+    state: &kmctrler::State,
+    chan: u8,
+) {
+    if state.start() {
+        return;
+    }
+    if state.wheel_up() {
+        octave_shift_up(settings, chan);
+    }
+    if state.wheel_down() {
+        octave_shift_down(settings, chan);
+    }
+}
+
 fn normal_mode_action(
     settings: &mut SynthesizerSettings,
     state: &kmctrler::State,
@@ -60,6 +78,35 @@ fn normal_mode_action(
     }
 }
 
+/// 調整モードで v3 にだけある操作
+///
+/// 録音と伴奏は status を見て切り替える。合図の音は処理の結果を受け取ってから鳴らす
+fn config_mode_extra_action(status: SynthStatus, key: u8) -> Option<Event> {
+    Some(match key {
+        // D#3
+        3 => Event::BackingTrack(if status.backing_track {
+            Transport::Stop
+        } else {
+            Transport::Start
+        }),
+        // F#3
+        6 => Event::BackingTrack(Transport::Rewind),
+        // F#4
+        18 => {
+            if status.recording {
+                Event::RecordingOff
+            } else {
+                Event::RecordingOn
+            }
+        }
+        // G#4
+        20 => Event::SplitMidiFile,
+        // A#4
+        22 => Event::DumpRollingBuffer,
+        _ => return None,
+    })
+}
+
 /// モード切替 .... Select + Start
 /// 演奏モード
 ///   オクターブシフト .... Start + WheelUp / WheelDown
@@ -67,82 +114,111 @@ fn normal_mode_action(
 ///   モジュレーション(ビブラート) .... Select
 /// 調整モード
 ///   チューニング .... Start + Key
+///   微調整 (1 セントずつ) .... Start + WheelUp / WheelDown
+///   音律の切り替え .... Select + WheelUp / WheelDown
+///   音律の主音 .... Select + Key
+///   基準ピッチ (1Hz ずつ) .... G#3 / A#3
+///   移調 (半音ずつ) .... WheelUp / WheelDown
 ///   プログラムチェンジ .... C#3 + Key / WheelUp / WheelDown
 ///   プログラムの音量の変更 .... C#3 + Key
 ///   リバーブ(toggle) .... C#4
 ///   コーラス(toggle) .... D#4
+///   鍵盤を分ける位置 (C3 で解除) .... C#3 + Start + Key
+///   左側のプログラムチェンジ .... C#3 + Start + WheelUp / WheelDown
+///   左側のオクターブ .... C#3 + Select + WheelUp / WheelDown
+///   録音(toggle) .... F#4
+///   演奏の SMF を書き出して次のファイルを始める .... G#4
+///   直近の演奏を SMF に書き出す .... A#4
+///   伴奏の再生・停止(toggle) .... D#3
+///   伴奏の巻き戻し .... F#3
+#[derive(Default)]
 pub struct SynthCtrler {
-    rx: mpsc::Receiver<(usize, kmctrler::Event)>,
-    settings: SynthesizerSettings,
     mode_config: bool,
+    /// 処理の結果を待って合図の音を鳴らす (イベント, チャンネル)
+    pending_cue: Option<(Event, u8)>,
     kmctrler_states: HashMap<u8, kmctrler::State>,
-    keydown_octave_table: HashMap<u8, [u8; 24]>,
+    keydown_octave_table: KeydownTable,
+    midi_keydown_table: HashMap<(u8, u8), u8>,
     event_queue: Vec<Event>,
 }
 
-impl SynthCtrler {
-    pub fn new(
-        settings: SynthesizerSettings,
-        rx: mpsc::Receiver<(usize, kmctrler::Event)>,
-    ) -> Self {
-        Self {
-            rx,
-            settings,
-            mode_config: false,
-            kmctrler_states: HashMap::new(),
-            keydown_octave_table: HashMap::new(),
-            event_queue: Vec::new(),
+impl Scheme for SynthCtrler {
+    fn handle(
+        &mut self,
+        settings: &mut SynthesizerSettings,
+        status: SynthStatus,
+        idx: usize,
+        ev: kmctrler::Event,
+    ) -> Option<Event> {
+        let chan = idx as u8;
+        let state = self.kmctrler_states.entry(chan).or_default();
+        // 切断で状態が消える前に戻す
+        if matches!(ev, kmctrler::Event::Disconnect) && !self.mode_config {
+            undo_temporary_octave_shift(settings, state, chan);
         }
-    }
+        state.update(&ev);
 
-    pub fn recv(&mut self) -> Result<Event, RecvError> {
-        if let Some(event) = self.event_queue.pop() {
-            if let Event::Noteoff(_, _) = event {
-                sleep(Duration::from_millis(100));
-            }
-            return Ok(event);
+        if let kmctrler::Event::Disconnect = ev {
+            self.keydown_octave_table.remove(&chan);
+            self.midi_keydown_table.retain(|&(x, _), _| x != chan);
+            return Some(disconnect(&mut self.event_queue, chan));
         }
-        loop {
-            let (idx, ev) = self.rx.recv()?;
-            let chan = idx as u8;
-            let state = self.kmctrler_states.entry(chan).or_default();
-            state.update(&ev);
-
-            if state.select() && state.start() {
-                self.mode_config = !self.mode_config;
-                state.reset_select_start();
-                return Ok(percussion(
-                    &mut self.event_queue,
-                    if self.mode_config { 1 } else { 0 },
-                ));
-            }
-            if self.mode_config {
-                match config_mode_action(
-                    &mut self.settings,
-                    &mut self.event_queue,
-                    state,
-                    chan,
-                    &ev,
-                ) {
-                    Ok(event) => return Ok(event),
-                    Err(true) => continue,
-                    Err(false) => {}
-                }
-            } else {
-                match normal_mode_action(&mut self.settings, state, chan, &ev) {
-                    Ok(event) => return Ok(event),
-                    Err(true) => continue,
-                    Err(false) => {}
+        if let Some(event) = midi_action(settings, &mut self.midi_keydown_table, chan, &ev) {
+            return Some(event);
+        }
+        if state.select() && state.start() {
+            self.mode_config = !self.mode_config;
+            state.reset_select_start();
+            let event = percussion(&mut self.event_queue, if self.mode_config { 1 } else { 0 });
+            return cue(&mut self.event_queue, event);
+        }
+        if self.mode_config && !state.keys()[1] && !state.start() && !state.select() {
+            if let kmctrler::Event::Press(Input::Key(key)) = ev {
+                if let Some(event) = config_mode_extra_action(status, key) {
+                    self.pending_cue = Some((event, chan));
+                    return Some(event);
                 }
             }
-            if let Some(event) = common_action(
-                &mut self.settings,
-                &mut self.keydown_octave_table,
-                chan,
-                &ev,
-            ) {
-                return Ok(event);
+        }
+        if self.mode_config {
+            match config_mode_action(settings, &mut self.event_queue, state, chan, &ev) {
+                Ok(event) => return cue(&mut self.event_queue, event),
+                Err(true) => return None,
+                Err(false) => {}
             }
+        } else {
+            match normal_mode_action(settings, state, chan, &ev) {
+                Ok(event) => return Some(event),
+                Err(true) => return None,
+                Err(false) => {}
+            }
+        }
+        common_action(settings, &mut self.keydown_octave_table, chan, &ev)
+    }
+
+    fn pop_event_queue(&mut self) -> Option<(Event, Duration)> {
+        pop_cue(&mut self.event_queue)
+    }
+
+    /// 止める操作と失敗したときは、止まったことを知らせる音を鳴らす
+    fn processed(&mut self, settings: &mut SynthesizerSettings, ev: Event, ok: bool) {
+        let Some((event, chan)) = self.pending_cue else {
+            return;
+        };
+        if event != ev {
+            return;
+        }
+        self.pending_cue = None;
+        let keyboard = settings.get_or_create_keyboard(chan);
+        if ok
+            && !matches!(
+                ev,
+                Event::RecordingOff | Event::BackingTrack(Transport::Stop)
+            )
+        {
+            add_on_sfx(&mut self.event_queue, chan, keyboard);
+        } else {
+            add_off_sfx(&mut self.event_queue, chan, keyboard);
         }
     }
 }
@@ -0,0 +1,393 @@
+use std::fs::read_to_string;
+
+use crate::settings::TemperamentSettings;
+
+/// 組み込みの音律の名前。設定ファイルの [[temperaments]] はこの後ろに並ぶ
+pub const PRESETS: [&str; 5] = ["equal", "just", "pythagorean", "meantone", "werckmeister"];
+
+/// 主音を指定しないときに基準にする鍵盤
+const MIDDLE_C: i32 = 60;
+/// 音律によらず基準ピッチにする鍵盤
+const A4: i32 = 69;
+
+fn ratio_to_cents(ratio: f64) -> f64 {
+    1200.0 * ratio.log2()
+}
+
+fn ratios(ratios: [(u32, u32); 12]) -> Vec<f64> {
+    ratios
+        .iter()
+        .skip(1)
+        .map(|&(n, d)| ratio_to_cents(n as f64 / d as f64))
+        .chain([1200.0])
+        .collect()
+}
+
+/// 主音から五度をいくつ重ねた音か
+const FIFTHS: [i32; 12] = [0, 7, 2, -3, 4, -1, 6, 1, 8, 3, -2, 5];
+
+fn preset(name: &str) -> Option<Vec<f64>> {
+    let scale = match name {
+        "equal" => (1..=12).map(|i| i as f64 * 100.0).collect(),
+        // 5 限界の純正律
+        "just" => ratios([
+            (1, 1),
+            (16, 15),
+            (9, 8),
+            (6, 5),
+            (5, 4),
+            (4, 3),
+            (45, 32),
+            (3, 2),
+            (8, 5),
+            (5, 3),
+            (9, 5),
+            (15, 8),
+        ]),
+        "pythagorean" => ratios([
+            (1, 1),
+            (256, 243),
+            (9, 8),
+            (32, 27),
+            (81, 64),
+            (4, 3),
+            (729, 512),
+            (3, 2),
+            (128, 81),
+            (27, 16),
+            (16, 9),
+            (243, 128),
+        ]),
+        // 1/4 シントニックコンマ・ミーントーン
+        "meantone" => {
+            let fifth = ratio_to_cents(1.5) - ratio_to_cents(81.0 / 80.0) / 4.0;
+            FIFTHS
+                .iter()
+                .skip(1)
+                .map(|&n| (n as f64 * fifth).rem_euclid(1200.0))
+                .chain([1200.0])
+                .collect()
+        }
+        // ヴェルクマイスター III
+        "werckmeister" => vec![
+            90.225, 192.18, 294.135, 390.225, 498.045, 588.27, 696.09, 792.18, 888.27, 996.09,
+            1092.18, 1200.0,
+        ],
+        _ => return None,
+    };
+    Some(scale)
+}
+
+/// Scala の音程の表記 (セントか比) を読む
+fn pitch(text: &str) -> Option<f64> {
+    if text.contains('.') {
+        return text.parse().ok();
+    }
+    let (n, d) = text.split_once('/').unwrap_or((text, "1"));
+    let (n, d) = (n.parse::<u32>().ok()?, d.parse::<u32>().ok()?);
+    (n > 0 && d > 0).then(|| ratio_to_cents(n as f64 / d as f64))
+}
+
+/// ! から始まるコメント行を除き、各行の最初の語を返す
+fn values(text: &str) -> impl Iterator<Item = &str> {
+    text.lines()
+        .filter(|line| !line.starts_with('!'))
+        .map(|line| line.split_whitespace().next().unwrap_or(""))
+}
+
+/// .scl を読む。1 度目からのセント値を返し、最後の値が周期になる
+fn parse_scl(text: &str) -> Result<Vec<f64>, String> {
+    // 最初の行は説明
+    let mut values = values(text).skip(1);
+    let count: usize = values
+        .next()
+        .and_then(|x| x.parse().ok())
+        .ok_or("invalid number of notes")?;
+    let scale = values
+        .take(count)
+        .map(|x| pitch(x).ok_or_else(|| format!("invalid pitch: {}", x)))
+        .collect::<Result<Vec<_>, _>>()?;
+    if scale.is_empty() || scale.len() < count {
+        return Err("too few pitches".to_owned());
+    }
+    Ok(scale)
+}
+
+/// Scala の .kbm (鍵盤と音階の度数の対応)
+struct KeyboardMapping {
+    first: i32,
+    last: i32,
+    middle: i32,
+    reference: i32,
+    frequency: f64,
+    octave_degree: usize,
+    /// 空なら鍵盤を順に度数へ割り当てる
+    mapping: Vec<Option<usize>>,
+}
+
+fn parse_kbm(text: &str) -> Result<KeyboardMapping, String> {
+    let mut values = values(text).filter(|x| !x.is_empty());
+    let mut next = |name: &str| values.next().ok_or(format!("missing {}", name));
+    let size: usize = next("map size")?.parse().map_err(|_| "invalid map size")?;
+    let mut key = |name: &str| -> Result<i32, String> {
+        let value = next(name)?;
+        value
+            .parse()
+            .map_err(|_| format!("invalid {}: {}", name, value))
+    };
+    let first = key("first note")?;
+    let last = key("last note")?;
+    let middle = key("middle note")?;
+    let reference = key("reference note")?;
+    let value = next("reference frequency")?;
+    let frequency: f64 = value
+        .parse()
+        .map_err(|_| format!("invalid reference frequency: {}", value))?;
+    let value = next("formal octave degree")?;
+    let octave_degree = value
+        .parse()
+        .map_err(|_| format!("invalid formal octave degree: {}", value))?;
+    // 足りない分は割り当てなしとみなす
+    let mapping = (0..size)
+        .map(|_| match values.next() {
+            None | Some("x") => Ok(None),
+            Some(x) => x
+                .parse()
+                .map(Some)
+                .map_err(|_| format!("invalid mapping: {}", x)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    // 基準の音が鳴らなければ全体の高さを決められない
+    let mapped = (first..=last).contains(&reference)
+        && (mapping.is_empty()
+            || mapping[(reference - middle).rem_euclid(mapping.len() as i32) as usize].is_some());
+    if !mapped {
+        return Err(format!("reference note {} is not mapped", reference));
+    }
+    Ok(KeyboardMapping {
+        first,
+        last,
+        middle,
+        reference,
+        frequency,
+        octave_degree,
+        mapping,
+    })
+}
+
+/// 鍵盤ごとの音程を決める音律
+pub struct Temperament {
+    /// 1 度目からのセント値。最後の値が周期
+    scale: Vec<f64>,
+    mapping: Option<KeyboardMapping>,
+}
+
+impl Temperament {
+    /// 組み込みの音律か、scl (と kbm) のファイルを読む。読めなければ平均律にする
+    pub fn load(settings: &TemperamentSettings) -> Self {
+        Self::read(settings).unwrap_or_else(|err| {
+            eprintln!("failed to load temperament {}: {}", settings.name(), err);
+            Self {
+                scale: preset("equal").unwrap(),
+                mapping: None,
+            }
+        })
+    }
+
+    fn read(settings: &TemperamentSettings) -> Result<Self, String> {
+        let Some(scl) = settings.scl() else {
+            let scale = preset(settings.name())
+                .ok_or_else(|| format!("unknown temperament: {}", settings.name()))?;
+            return Ok(Self {
+                scale,
+                mapping: None,
+            });
+        };
+        let read = |path: &str| read_to_string(path).map_err(|err| format!("{}: {}", path, err));
+        let scale = parse_scl(&read(scl)?).map_err(|err| format!("{}: {}", scl, err))?;
+        let mapping = settings
+            .kbm()
+            .as_deref()
+            .map(|kbm| parse_kbm(&read(kbm)?).map_err(|err| format!("{}: {}", kbm, err)))
+            .transpose()?;
+        Ok(Self { scale, mapping })
+    }
+
+    /// 0 度からのセント値。周期を越える度数も受け付ける
+    fn degree(&self, degree: i32) -> f64 {
+        let len = self.scale.len() as i32;
+        let period = self.scale[self.scale.len() - 1];
+        let step = match degree.rem_euclid(len) {
+            0 => 0.0,
+            x => self.scale[x as usize - 1],
+        };
+        degree.div_euclid(len) as f64 * period + step
+    }
+
+    /// middle からのセント値。割り当ての無い鍵盤は None
+    fn key(&self, key: i32, middle: i32) -> Option<f64> {
+        let Some(mapping) = &self.mapping else {
+            return Some(self.degree(key - middle));
+        };
+        if key < mapping.first || key > mapping.last {
+            return None;
+        }
+        if mapping.mapping.is_empty() {
+            return Some(self.degree(key - middle));
+        }
+        let size = mapping.mapping.len() as i32;
+        let offset = key - middle;
+        let degree = mapping.mapping[offset.rem_euclid(size) as usize]?;
+        Some(
+            offset.div_euclid(size) as f64 * self.degree(mapping.octave_degree as i32)
+                + self.degree(degree as i32),
+        )
+    }
+
+    /// 各鍵盤の音程を FluidSynth のチューニングの単位 (セント、A4 が 6900) で返す
+    ///
+    /// root は主音の音名 (0 が C)。kbm があれば主音と基準の音はそちらに従う。
+    /// a4_hz が 440 でなければ、kbm の基準の周波数も含めて全体を同じ比率でずらす。
+    /// 割り当ての無い鍵盤は平均律のままにする
+    pub fn pitches(&self, root: u8, a4_hz: u16) -> Vec<f64> {
+        let (middle, reference, frequency) = match &self.mapping {
+            Some(mapping) => (mapping.middle, mapping.reference, mapping.frequency),
+            None => (MIDDLE_C + root as i32, A4, 440.0),
+        };
+        let shift = ratio_to_cents(a4_hz as f64 / 440.0);
+        let origin = A4 as f64 * 100.0 + ratio_to_cents(frequency / 440.0) + shift
+            - self.key(reference, middle).unwrap_or(0.0);
+        (0..128)
+            .map(|key| {
+                self.key(key, middle)
+                    .map_or(key as f64 * 100.0 + shift, |cents| origin + cents)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCL: &str = include_str!("../fixtures/just.scl");
+    const KBM: &str = include_str!("../fixtures/white_keys.kbm");
+
+    fn assert_cents(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-6,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn scl_reads_ratios_and_cents() {
+        let scale = parse_scl(SCL).unwrap();
+        assert_eq!(scale.len(), 12);
+        assert_cents(scale[0], ratio_to_cents(16.0 / 15.0));
+        assert_cents(scale[3], ratio_to_cents(5.0 / 4.0));
+        assert_cents(scale[6], 701.955);
+        assert_cents(scale[11], 1200.0);
+        assert!(parse_scl("too few\n 3\n 9/8\n 2/1\n").is_err());
+        assert!(parse_scl("bad pitch\n 1\n 0/1\n").is_err());
+    }
+
+    #[test]
+    fn kbm_reads_the_mapping() {
+        let mapping = parse_kbm(KBM).unwrap();
+        assert_eq!(
+            (
+                mapping.first,
+                mapping.last,
+                mapping.middle,
+                mapping.reference
+            ),
+            (0, 127, 60, 69)
+        );
+        assert_cents(mapping.frequency, 440.0);
+        assert_eq!(mapping.octave_degree, 12);
+        assert_eq!(
+            mapping.mapping,
+            [
+                Some(0),
+                None,
+                Some(2),
+                None,
+                Some(4),
+                Some(5),
+                None,
+                Some(7),
+                None,
+                Some(9),
+                None,
+                Some(11)
+            ]
+        );
+    }
+
+    #[test]
+    fn kbm_rejects_an_unmapped_reference_note() {
+        // A#4 は割り当てが無い
+        let kbm = KBM.replacen("\n69\n", "\n70\n", 1);
+        assert_eq!(
+            parse_kbm(&kbm).err().as_deref(),
+            Some("reference note 70 is not mapped")
+        );
+        // 書き換える範囲の外
+        let kbm = KBM.replacen("\n127\n", "\n68\n", 1);
+        assert!(parse_kbm(&kbm).is_err());
+    }
+
+    #[test]
+    fn equal_temperament_is_key_times_100() {
+        let temperament = Temperament {
+            scale: preset("equal").unwrap(),
+            mapping: None,
+        };
+        for root in 0..12 {
+            for (key, cents) in temperament.pitches(root, 440).into_iter().enumerate() {
+                assert_cents(cents, key as f64 * 100.0);
+            }
+        }
+    }
+
+    #[test]
+    fn presets_keep_a4_at_6900() {
+        for name in PRESETS {
+            let temperament = Temperament {
+                scale: preset(name).unwrap(),
+                mapping: None,
+            };
+            for root in 0..12 {
+                let pitches = temperament.pitches(root, 440);
+                assert_eq!(pitches.len(), 128);
+                assert_cents(pitches[A4 as usize], 6900.0);
+                // 主音からの 1 オクターブは純正
+                let tonic = MIDDLE_C as usize + root as usize;
+                assert_cents(pitches[tonic + 12] - pitches[tonic], 1200.0);
+            }
+            let pitches = temperament.pitches(0, 442);
+            assert_cents(pitches[A4 as usize], 6900.0 + ratio_to_cents(442.0 / 440.0));
+        }
+    }
+
+    #[test]
+    fn scl_with_kbm_pitches() {
+        let temperament = Temperament {
+            scale: parse_scl(SCL).unwrap(),
+            mapping: Some(parse_kbm(KBM).unwrap()),
+        };
+        let pitches = temperament.pitches(0, 440);
+        assert_cents(pitches[69], 6900.0);
+        let c4 = 6900.0 - ratio_to_cents(5.0 / 3.0);
+        assert_cents(pitches[60], c4);
+        assert_cents(pitches[64], c4 + ratio_to_cents(5.0 / 4.0));
+        assert_cents(pitches[67], c4 + 701.955);
+        assert_cents(pitches[72], c4 + 1200.0);
+        assert_cents(pitches[48], c4 - 1200.0);
+        // 割り当ての無い鍵盤は平均律のまま
+        assert_cents(pitches[61], 6100.0);
+    }
+}